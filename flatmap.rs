@@ -5,15 +5,34 @@
 
 //extern mod extra;
 
-use std::vec;
-use std::util::replace;
 use std::cast;
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::io::Writer;
+use std::iterator::Invert;
+use std::libc;
+#[cfg(unix)] use std::os;
+#[cfg(unix)] use std::os::{MemoryMap, MapReadable, MapFd};
+use std::ptr;
+use std::sys;
+use std::task;
+use std::to_bytes;
+use std::uint;
+use std::vec;
+use std::c_str::ToCStr;
+use std::util::{replace, swap};
+use std::unstable::atomics::{AtomicPtr, AtomicUint, Relaxed, Release, Acquire, SeqCst};
+use std::unstable::intrinsics;
+use std::unstable::raw;
+use std::unstable::sync::UnsafeArc;
 
 
-// add FlatSet<K>
-
+/// Return the index of the first element of `a` for which `less` is false.
+/// `a` must be partitioned so that all elements for which `less` is true
+/// come first.
 #[inline]
-fn lower_bound_index<K: TotalOrd, V>(a: &[(K,V)], key: &K) -> uint {
+fn lower_bound_by<T>(a: &[T], less: &fn(&T) -> bool) -> uint {
     let mut count = a.len();
     let mut first = 0u;
     let mut it;
@@ -22,8 +41,7 @@ fn lower_bound_index<K: TotalOrd, V>(a: &[(K,V)], key: &K) -> uint {
         it = first;
         step = count / 2;
         it += step;
-        let (ref k, _) = a[it];
-        if k.cmp(key) == Less {
+        if less(&a[it]) {
             first = it + 1;
             count -= step + 1;
         } else {
@@ -33,14 +51,411 @@ fn lower_bound_index<K: TotalOrd, V>(a: &[(K,V)], key: &K) -> uint {
     first
 }
 
+/// The zero based index of the `p`th percentile of `n` ordered values by the
+/// nearest rank method.
+fn nearest_rank(p: f64, n: uint) -> uint {
+    if !(p >= 0.0 && p <= 100.0) {
+        fail2!("percentile out of range: {}", p);
+    }
+    let rank = (p * n as f64 / 100.0).ceil() as uint;
+    if rank == 0 { 0 } else { rank - 1 }
+}
+
+/// Why a fallible allocation failed.
+#[deriving(Clone, Eq)]
+pub enum AllocError {
+    /// The requested capacity does not fit in the address space.
+    CapacityOverflow,
+    /// The allocator could not provide the memory.
+    OutOfMemory,
+}
+
+/// The memory held by a map, in bytes. Memory owned by the keys and
+/// values themselves, such as the contents of strings, is not counted.
+#[deriving(Clone, Eq)]
+pub struct MemoryUsage {
+    /// Bytes used by the pairs in the map.
+    used: uint,
+    /// Bytes allocated for pairs, including spare capacity.
+    allocated: uint,
+}
+
+/// Grow `v` so it can hold at least `additional` more elements, doubling
+/// its capacity if that is more, without failing when memory runs out.
+/// The size of the new allocation is checked for overflow, and a trial
+/// allocation of that size just before `reserve` makes sure the memory is
+/// there, unless another thread takes it in between.
+fn try_reserve_vec<T>(v: &mut ~[T], additional: uint) -> Result<(), AllocError> {
+    let needed = match checked_add(v.len(), additional) {
+        Some(n) => n,
+        None => return Err(CapacityOverflow),
+    };
+    if needed <= v.capacity() {
+        return Ok(());
+    }
+    let doubled = if v.capacity() > uint::max_value / 2 { needed } else { v.capacity() * 2 };
+    let capacity = cmp::max(needed, doubled);
+    let bytes = match checked_mul(capacity, sys::size_of::<T>()) {
+        Some(n) => checked_add(n, sys::size_of::<raw::Vec<()>>()),
+        None => None,
+    };
+    match bytes {
+        Some(bytes) => unsafe {
+            let ptr = libc::malloc(bytes as libc::size_t);
+            if ptr.is_null() {
+                return Err(OutOfMemory);
+            }
+            libc::free(ptr);
+        },
+        None => return Err(CapacityOverflow),
+    }
+    v.reserve(capacity);
+    Ok(())
+}
+
+/// An ordering of the keys of a FlatMapBy. The ordering is a type parameter
+/// of the map, so comparing through a zero-sized ordering such as
+/// `NaturalOrder` is a static call which can be inlined into the search.
+pub trait Compare<K>: Clone {
+    /// Compare two keys.
+    fn compare(&self, a: &K, b: &K) -> Ordering;
+
+    /// Return the index of the first pair in `a`, which is sorted by this
+    /// ordering, whose key is not less than `key`. Every lookup of a
+    /// FlatMapBy goes through this search, so an ordering can replace the
+    /// binary search with one suited to its keys.
+    #[inline]
+    fn lower_bound<V>(&self, a: &[(K, V)], key: &K) -> uint {
+        lower_bound_by(a, |&(ref k, _)| self.compare(k, key) == Less)
+    }
+}
+
+/// The natural order of keys, which FlatMap always uses.
+#[deriving(Clone, Eq)]
+pub struct NaturalOrder;
+
+impl<K: TotalOrd> Compare<K> for NaturalOrder {
+    #[inline]
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// A function which orders the keys of a FlatMap.
+pub type Comparator<K> = extern fn(&K, &K) -> Ordering;
+
+/// An ordering given by a function chosen at run time. Each comparison is
+/// an indirect call through the function pointer.
+pub struct OrderBy<K>(Comparator<K>);
+
+impl<K> Clone for OrderBy<K> {
+    fn clone(&self) -> OrderBy<K> {
+        OrderBy(**self)
+    }
+}
+
+impl<K> Compare<K> for OrderBy<K> {
+    #[inline]
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        (**self)(a, b)
+    }
+}
+
+#[inline]
+fn lower_bound_index<K, V, C: Compare<K>>(a: &[(K,V)], key: &K, compare: &C) -> uint {
+    compare.lower_bound(a, key)
+}
+
+#[inline]
+fn upper_bound_index<K, V, C: Compare<K>>(a: &[(K,V)], key: &K, compare: &C) -> uint {
+    lower_bound_by(a, |&(ref k, _)| compare.compare(k, key) != Greater)
+}
+
+#[inline]
+fn keys_lower_bound_index<K: TotalOrd>(a: &[K], key: &K) -> uint {
+    lower_bound_by(a, |k| k.cmp(key) == Less)
+}
+
+#[inline]
+fn keys_upper_bound_index<K: TotalOrd>(a: &[K], key: &K) -> uint {
+    lower_bound_by(a, |k| k.cmp(key) != Greater)
+}
+
+fn keys_range_indices<K: TotalOrd>(a: &[K], lo: Bound<K>, hi: Bound<K>) -> (uint, uint) {
+    let start = match lo {
+        Included(k) => keys_lower_bound_index(a, k),
+        Excluded(k) => keys_upper_bound_index(a, k),
+        Unbounded => 0,
+    };
+    let end = match hi {
+        Included(k) => keys_upper_bound_index(a, k),
+        Excluded(k) => keys_lower_bound_index(a, k),
+        Unbounded => a.len(),
+    };
+    if end < start {
+        (start, start)
+    } else {
+        (start, end)
+    }
+}
+
+/// An equivalence between a borrowed lookup type and a key type which also
+/// agrees with the key ordering, so lookups such as searching a
+/// `FlatMap<~str, V>` by `&str` can binary search.
+pub trait OrdEquiv<K>: Equiv<K> {
+    /// Compare `self` with a key.
+    fn equiv_cmp(&self, other: &K) -> Ordering;
+}
+
+impl<'self> OrdEquiv<~str> for &'self str {
+    #[inline]
+    fn equiv_cmp(&self, other: &~str) -> Ordering {
+        let a: &str = *self;
+        a.cmp(&other.as_slice())
+    }
+}
+
+impl<'self, T: Eq + TotalOrd> OrdEquiv<~[T]> for &'self [T] {
+    #[inline]
+    fn equiv_cmp(&self, other: &~[T]) -> Ordering {
+        let a: &[T] = *self;
+        a.cmp(&other.as_slice())
+    }
+}
+
+/// One end of a key range.
+pub enum Bound<'self, K> {
+    /// The range includes the key.
+    Included(&'self K),
+    /// The range excludes the key.
+    Excluded(&'self K),
+    /// The range is open on this end.
+    Unbounded,
+}
+
+/// How to resolve pairs with equal keys when building a FlatMap in bulk.
+pub enum Duplicates<'self, K, V> {
+    /// Keep the value that was seen first.
+    KeepFirst,
+    /// Keep the value that was seen last, as repeated `insert` calls would.
+    KeepLast,
+    /// Combine the earlier and the later value.
+    MergeWith(&'self fn(&K, V, V) -> V),
+}
+
+static INSERTION_SORT_THRESHOLD: uint = 16;
+
+#[inline]
+fn key_at<'a, K, V>(a: &'a [(K, V)], i: uint) -> &'a K {
+    let (ref k, _) = a[i];
+    k
+}
+
+fn is_sorted_by_key<K, V>(a: &[(K, V)], cmp: &fn(&K, &K) -> Ordering) -> bool {
+    range(1, a.len()).all(|i| cmp(key_at(a, i - 1), key_at(a, i)) != Greater)
+}
+
+fn insertion_sort_by_key<K, V>(a: &mut [(K, V)], cmp: &fn(&K, &K) -> Ordering) {
+    for i in range(1, a.len()) {
+        let mut j = i;
+        while j > 0 && cmp(key_at(a, j - 1), key_at(a, j)) == Greater {
+            a.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+}
+
+/// Stable merge of two vectors sorted by key. Pairs from `a` come before
+/// pairs from `b` with an equal key.
+fn merge_runs<K, V>(a: ~[(K, V)], b: ~[(K, V)],
+                    cmp: &fn(&K, &K) -> Ordering) -> ~[(K, V)] {
+    let mut out = vec::with_capacity(a.len() + b.len());
+    let mut ia = a.move_iter();
+    let mut ib = b.move_iter();
+    let mut x = ia.next();
+    let mut y = ib.next();
+    loop {
+        let take_a = match (&x, &y) {
+            (&None, &None) => break,
+            (&Some(_), &None) => true,
+            (&None, &Some(_)) => false,
+            (&Some((ref ka, _)), &Some((ref kb, _))) => cmp(ka, kb) != Greater,
+        };
+        if take_a {
+            out.push(replace(&mut x, ia.next()).unwrap());
+        } else {
+            out.push(replace(&mut y, ib.next()).unwrap());
+        }
+    }
+    out
+}
+
+fn merge_sort_by_key<K, V>(data: ~[(K, V)],
+                           cmp: &fn(&K, &K) -> Ordering) -> ~[(K, V)] {
+    let len = data.len();
+    if len <= INSERTION_SORT_THRESHOLD {
+        let mut data = data;
+        insertion_sort_by_key(data, cmp);
+        return data;
+    }
+    let mid = len / 2;
+    let mut left = vec::with_capacity(mid);
+    let mut right = vec::with_capacity(len - mid);
+    for (i, pair) in data.move_iter().enumerate() {
+        if i < mid {
+            left.push(pair);
+        } else {
+            right.push(pair);
+        }
+    }
+    merge_runs(merge_sort_by_key(left, cmp), merge_sort_by_key(right, cmp), cmp)
+}
+
+/// Stable O(n log n) sort of `data` by key. Input which is already sorted
+/// is only scanned once.
+fn sort_by_key<K, V>(data: ~[(K, V)], cmp: &fn(&K, &K) -> Ordering) -> ~[(K, V)] {
+    if is_sorted_by_key(data, cmp) {
+        data
+    } else {
+        merge_sort_by_key(data, cmp)
+    }
+}
+
+/// Collapse runs of equal keys in `data`, which must be sorted by key. The
+/// first key of a run is kept; the value is chosen by `dups`.
+fn dedup_sorted<K, V>(data: ~[(K, V)], cmp: &fn(&K, &K) -> Ordering,
+                      dups: &Duplicates<K, V>) -> ~[(K, V)] {
+    let mut out: ~[(K, V)] = vec::with_capacity(data.len());
+    for (k, v) in data.move_iter() {
+        let n = out.len();
+        if n > 0 && cmp(key_at(out, n - 1), &k) == Equal {
+            let (last_k, last_v) = out.pop();
+            let value = match *dups {
+                KeepFirst => last_v,
+                KeepLast => v,
+                MergeWith(ref f) => (*f)(&last_k, last_v, v),
+            };
+            out.push((last_k, value));
+        } else {
+            out.push((k, v));
+        }
+    }
+    out
+}
+
+/// Move the sorted runs `src[start..mid]` and `src[mid..end]` to
+/// `dst[start..end]`, merged stably by key.
+unsafe fn merge_raw<K, V>(src: *mut (K, V), dst: *mut (K, V), start: uint, mid: uint,
+                          end: uint, cmp: &fn(&K, &K) -> Ordering) {
+    let a: &[(K, V)] = cast::transmute(raw::Slice{data: src as *(K, V), len: end});
+    let (mut i, mut j) = (start, mid);
+    for out in range(start, end) {
+        let from = if j == end || (i < mid && cmp(key_at(a, i), key_at(a, j)) != Greater) {
+            i += 1;
+            i - 1
+        } else {
+            j += 1;
+            j - 1
+        };
+        ptr::copy_nonoverlapping_memory(ptr::mut_offset(dst, out as int),
+                                        ptr::offset(src as *(K, V), from as int), 1);
+    }
+}
+
+/// Stable merge sort of `data` by key, using the spare capacity of the
+/// empty `scratch`, which must have room for as many pairs, so nothing is
+/// allocated. The lengths are zero while the pairs move between the
+/// vectors, so if `cmp` fails the pairs leak rather than being dropped
+/// twice.
+fn sort_in_place<K, V>(data: &mut ~[(K, V)], scratch: &mut ~[(K, V)],
+                       cmp: &fn(&K, &K) -> Ordering) {
+    let n = data.len();
+    if is_sorted_by_key(*data, cmp) {
+        return;
+    }
+    unsafe {
+        let home = vec::raw::to_mut_ptr(*data);
+        let mut src = home;
+        let mut dst = vec::raw::to_mut_ptr(*scratch);
+        vec::raw::set_len(data, 0);
+        let mut start = 0;
+        while start < n {
+            let len = cmp::min(INSERTION_SORT_THRESHOLD, n - start);
+            let run: &mut [(K, V)] = cast::transmute(
+                raw::Slice{data: ptr::offset(src as *(K, V), start as int), len: len});
+            insertion_sort_by_key(run, cmp);
+            start += len;
+        }
+        // merge runs of doubling width back and forth between the vectors
+        let mut width = INSERTION_SORT_THRESHOLD;
+        while width < n {
+            let mut start = 0;
+            while start < n {
+                let mid = start + cmp::min(width, n - start);
+                let end = mid + cmp::min(width, n - mid);
+                merge_raw(src, dst, start, mid, end, cmp);
+                start = end;
+            }
+            swap(&mut src, &mut dst);
+            width *= 2;
+        }
+        if src != home {
+            ptr::copy_nonoverlapping_memory(home, src as *(K, V), n);
+        }
+        vec::raw::set_len(data, n);
+    }
+}
+
+/// Collapse runs of equal keys in `data`, which must be sorted by key, like
+/// `dedup_sorted` but moving the pairs within `data`, so nothing is
+/// allocated.
+fn dedup_in_place<K, V>(data: &mut ~[(K, V)], cmp: &fn(&K, &K) -> Ordering,
+                        dups: &Duplicates<K, V>) {
+    let n = data.len();
+    unsafe {
+        let p = vec::raw::to_mut_ptr(*data);
+        // if `dups` fails, the pairs not yet moved leak rather than being
+        // dropped twice
+        vec::raw::set_len(data, 0);
+        let mut kept = 0u;
+        for i in range(0, n) {
+            let (k, v) = ptr::read_ptr(ptr::offset(p as *(K, V), i as int));
+            let last: &[(K, V)] = cast::transmute(raw::Slice{data: p as *(K, V), len: kept});
+            if kept > 0 && cmp(key_at(last, kept - 1), &k) == Equal {
+                let slot = ptr::mut_offset(p, (kept - 1) as int);
+                let (last_k, last_v) = ptr::read_ptr(slot as *(K, V));
+                let value = match *dups {
+                    KeepFirst => last_v,
+                    KeepLast => v,
+                    MergeWith(ref f) => (*f)(&last_k, last_v, v),
+                };
+                intrinsics::move_val_init(&mut *slot, (last_k, value));
+            } else {
+                intrinsics::move_val_init(&mut *ptr::mut_offset(p, kept as int), (k, v));
+                kept += 1;
+            }
+        }
+        vec::raw::set_len(data, kept);
+    }
+}
+
 /// A flat map implementation which stores key value pairs as an array of
-/// tuples in a sorted vector ~[(K, V)].
-#[deriving(Clone)]
+/// tuples in a sorted vector ~[(K, V)]. Keys are in their natural order;
+/// FlatMapBy orders them by a comparator.
 pub struct FlatMap<K, V> {
-    priv data: ~[(K, V)],
+    priv map: FlatMapBy<K, V, NaturalOrder>,
 }
 
 impl<K: TotalOrd, V> FlatMap<K, V> {
+    fn from_data(data: ~[(K, V)]) -> FlatMap<K, V> {
+        FlatMap{map: FlatMapBy{data: data, compare: NaturalOrder}}
+    }
+
+    fn into_data(self) -> ~[(K, V)] {
+        let FlatMap{map: FlatMapBy{data, _}} = self;
+        data
+    }
+
     /// Creates an empty FlatMap.
     pub fn new() -> FlatMap<K, V> {
         FlatMap::with_capacity(0)
@@ -48,48 +463,183 @@ impl<K: TotalOrd, V> FlatMap<K, V> {
 
     /// Create an empty FlatMap with space for at least `n` elements.
     pub fn with_capacity(capacity: uint) -> FlatMap<K, V> {
-        FlatMap{data: vec::with_capacity(capacity)}
+        FlatMap{map: FlatMapBy::with_capacity_and_comparator(capacity, NaturalOrder)}
+    }
+
+    /// Create a FlatMap from pairs in any order in O(n log n), resolving
+    /// equal keys according to `dups`.
+    pub fn from_vec(data: ~[(K, V)], dups: Duplicates<K, V>) -> FlatMap<K, V> {
+        let mut map = FlatMap::new();
+        map.extend_with(&mut data.move_iter(), dups);
+        map
+    }
+
+    /// Create a FlatMap from pairs which are already sorted by strictly
+    /// ascending key, without sorting them again. Fails if `data` is not
+    /// sorted or contains equal keys.
+    pub fn from_sorted_vec(data: ~[(K, V)]) -> FlatMap<K, V> {
+        for i in range(1, data.len()) {
+            if key_at(data, i - 1).cmp(key_at(data, i)) != Less {
+                fail2!("FlatMap::from_sorted_vec: input is not sorted at index {}", i);
+            }
+        }
+        FlatMap::from_data(data)
+    }
+
+    /// Create a FlatMap from an iterator of pairs in O(n log n), resolving
+    /// equal keys according to `dups`.
+    pub fn from_iter_with<T: Iterator<(K, V)>>(iter: &mut T, dups: Duplicates<K, V>)
+                                              -> FlatMap<K, V> {
+        let data: ~[(K, V)] = iter.collect();
+        FlatMap::from_vec(data, dups)
+    }
+
+    /// Create a FlatMap from the pairs of an iterator like `from_iter_with`,
+    /// returning an error instead of failing if memory runs out. Sorting
+    /// needs scratch space the size of the pairs, which is reserved before
+    /// sorting starts, so nothing is allocated after that.
+    pub fn try_from_iter<T: Iterator<(K, V)>>(iter: &mut T, dups: Duplicates<K, V>)
+                                              -> Result<FlatMap<K, V>, AllocError> {
+        let mut data = ~[];
+        loop {
+            match iter.next() {
+                Some(pair) => {
+                    match try_reserve_vec(&mut data, 1) {
+                        Ok(()) => data.push(pair),
+                        Err(e) => return Err(e),
+                    }
+                }
+                None => break,
+            }
+        }
+        let mut scratch = ~[];
+        match try_reserve_vec(&mut scratch, data.len()) {
+            Ok(()) => (),
+            Err(e) => return Err(e),
+        }
+        let cmp: &fn(&K, &K) -> Ordering = |a, b| a.cmp(b);
+        sort_in_place(&mut data, &mut scratch, cmp);
+        dedup_in_place(&mut data, cmp, &dups);
+        Ok(FlatMap::from_data(data))
+    }
+
+    /// Create a FlatMap from a FlatMapBy in natural order.
+    pub fn from_flatmap_by(map: FlatMapBy<K, V, NaturalOrder>) -> FlatMap<K, V> {
+        FlatMap{map: map}
+    }
+
+    /// Convert into a FlatMapBy in natural order, for code which is generic
+    /// over the ordering.
+    pub fn into_flatmap_by(self) -> FlatMapBy<K, V, NaturalOrder> {
+        self.map
+    }
+
+    /// Add the pairs of an iterator to the map in O((n + m) log m), where
+    /// `m` is the number of new pairs. Keys already in the map count as
+    /// seen first when resolving equal keys according to `dups`.
+    pub fn extend_with<T: Iterator<(K, V)>>(&mut self, iter: &mut T,
+                                            dups: Duplicates<K, V>) {
+        self.map.extend_with(iter, dups)
+    }
+
+    /// Combine two maps in O(n + m), calling `f` with the key and both
+    /// values, this map's first, for each key the maps have in common.
+    pub fn merge(self, other: FlatMap<K, V>, f: &fn(&K, V, V) -> V) -> FlatMap<K, V> {
+        FlatMap{map: self.map.merge(other.map, f)}
+    }
+
+    /// Move all pairs of `other` into this map in O(n + m). Values from
+    /// `other` replace those of equal keys, as inserting each pair would.
+    pub fn append(&mut self, other: FlatMap<K, V>) {
+        self.map.append(other.map)
     }
 
     /// Return the capacity of the underlying vector.
     pub fn capacity(&self) -> uint {
-        self.data.capacity()
+        self.map.capacity()
+    }
+
+    /// Keep only the pairs for which `f` returns true, in one pass.
+    pub fn retain(&mut self, f: &fn(&K, &V) -> bool) {
+        self.map.retain(f)
+    }
+
+    /// Remove the pairs whose key lies between `lo` and `hi` in O(n),
+    /// returning them in order.
+    pub fn drain_range(&mut self, lo: Bound<K>, hi: Bound<K>) -> ~[(K, V)] {
+        self.map.drain_range(lo, hi)
+    }
+
+    /// Split the map in two at `key`, leaving the pairs whose key is less
+    /// than `key` in this map and returning the rest.
+    pub fn split_off(&mut self, key: &K) -> FlatMap<K, V> {
+        FlatMap{map: self.map.split_off(key)}
+    }
+
+    /// Remove pairs from the front of the map, keeping the last `len`.
+    pub fn truncate_front(&mut self, len: uint) {
+        self.map.truncate_front(len)
+    }
+
+    /// Remove pairs from the back of the map, keeping the first `len`.
+    pub fn truncate_back(&mut self, len: uint) {
+        self.map.truncate_back(len)
+    }
+
+    /// Collapse each run of keys for which `same` returns true when called
+    /// with the first key of the run into that first key, resolving the
+    /// values according to `dups`.
+    pub fn dedup_by(&mut self, same: &fn(&K, &K) -> bool, dups: Duplicates<K, V>) {
+        self.map.dedup_by(same, dups)
+    }
+
+    /// Make room for at least `additional` more pairs, returning an error
+    /// instead of failing if memory runs out.
+    pub fn try_reserve(&mut self, additional: uint) -> Result<(), AllocError> {
+        self.map.try_reserve(additional)
+    }
+
+    /// Insert a key-value pair like `insert`, returning an error instead of
+    /// failing if memory runs out. Replacing the value of a key which is
+    /// already present never allocates.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<bool, AllocError> {
+        self.map.try_insert(key, value)
+    }
+
+    /// Release the spare capacity of the underlying vector.
+    pub fn shrink_to_fit(&mut self) {
+        self.map.shrink_to_fit()
+    }
+
+    /// Report how much memory the map holds for its pairs.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.map.memory_usage()
+    }
+
+    /// Get the entry for a key in the map for in-place manipulation. The key
+    /// is searched for once and the entry remembers where it was found.
+    pub fn entry<'a>(&'a mut self, key: K) -> Entry<'a, K, V, NaturalOrder> {
+        self.map.entry(key)
     }
 
     /// Modify and return the value corresponding to the key in the map, or
     /// insert and return a new value if it doesn't exist.
     pub fn mangle<'a,A>(&'a mut self, k: K, a: A, not_found: &fn(&K, A) -> V,
                         found: &fn(&K, &mut V, A)) -> &'a mut V {
-        // work around https://github.com/mozilla/rust/issues/6393
-        let self2: &mut FlatMap<K, V> = unsafe {
-            cast::transmute_copy(&self)
-        };
-        match self.find_mut(&k) {
-            Some(val) => {
-                found(&k, val, a);
-                val
-            }
-            None => {
-                let v = not_found(&k, a);
-                let index = lower_bound_index(self2.data, &k);
-                self2.data.insert(index, (k, v));
-                let (_, ref val) = self2.data[index];
-                unsafe { cast::transmute(val) }
-            }
-        }
+        self.map.mangle(k, a, not_found, found)
     }
 
     /// Return the value corresponding to the key in the map, or insert
     /// and return the value if it doesn't exist.
     pub fn find_or_insert<'a>(&'a mut self, k: K, v: V) -> &'a mut V {
-        self.mangle(k, v, |_k, a| a, |_k,_v,_a| ())
+        self.map.find_or_insert(k, v)
     }
 
     /// Return the value corresponding to the key in the map, or create,
     /// insert, and return a new value if it doesn't exist.
     pub fn find_or_insert_with<'a>(&'a mut self, k: K, f: &fn(&K) -> V)
                                -> &'a mut V {
-        self.mangle(k, (), |k,_a| f(k), |_k,_v,_a| ())
+        self.map.find_or_insert_with(k, f)
     }
 
     /// Insert a key-value pair into the map if the key is not already present.
@@ -97,89 +647,164 @@ impl<K: TotalOrd, V> FlatMap<K, V> {
     /// Returns the new or modified value for the key.
     pub fn insert_or_update_with<'a>(&'a mut self, k: K, v: V,
                                      f: &fn(&K, &mut V)) -> &'a mut V {
-        self.mangle(k, v, |_k,a| a, |k,v,_a| f(k,v))
-    }
-
-    /// Retrieves a value for the given key, failing if the key is not
-    /// present.
-    pub fn get<'a>(&'a self, k: &K) -> &'a V {
-        match self.find(k) {
-            Some(v) => v,
-            None => fail2!("No entry found for key: {:?}", k),
-        }
-    }
-
-    /// Retrieves a (mutable) value for the given key, failing if the key
-    /// is not present.
-    pub fn get_mut<'a>(&'a mut self, k: &K) -> &'a mut V {
-        match self.find_mut(k) {
-            Some(v) => v,
-            None => fail2!("No entry found for key: {:?}", k),
-        }
+        self.map.insert_or_update_with(k, v, f)
     }
 
     /// Return the value corresponding to the key in the map, using
-    /// equivalence
-    pub fn find_equiv<'a, Q: Equiv<K>>(&'a self, key: &Q)
+    /// equivalence.
+    pub fn find_equiv<'a, Q: OrdEquiv<K>>(&'a self, key: &Q)
                                              -> Option<&'a V> {
-        for &(ref k, ref v) in self.data.iter() {
-            if key.equiv(k) {
-                return Some(v)
-            }
-        }
-        None
+        self.map.find_equiv(key)
     }
 
     /// An iterator visiting all key-value pairs in order.
     /// Iterator element type is (&'a K, &'a V).
     pub fn iter<'a>(&'a self) -> FlatMapIterator<'a, K, V> {
-        FlatMapIterator{iter: self.data.iter()}
+        self.map.iter()
+    }
+
+    /// An iterator visiting the key-value pairs whose key is not less than
+    /// `key`, in order.
+    pub fn lower_bound<'a>(&'a self, key: &K) -> FlatMapIterator<'a, K, V> {
+        self.map.lower_bound(key)
+    }
+
+    /// An iterator visiting the key-value pairs whose key is greater than
+    /// `key`, in order.
+    pub fn upper_bound<'a>(&'a self, key: &K) -> FlatMapIterator<'a, K, V> {
+        self.map.upper_bound(key)
+    }
+
+    /// An iterator visiting the key-value pairs whose key lies between
+    /// `lo` and `hi`, in order.
+    pub fn range<'a>(&'a self, lo: Bound<K>, hi: Bound<K>)
+                     -> FlatMapIterator<'a, K, V> {
+        self.map.range(lo, hi)
+    }
+
+    /// Return the number of keys in the map which are less than `key`.
+    pub fn rank(&self, key: &K) -> uint {
+        self.map.rank(key)
+    }
+
+    /// Return the pair at position `n` in order, or None if the map has
+    /// `n` or fewer pairs.
+    pub fn select<'a>(&'a self, n: uint) -> Option<(&'a K, &'a V)> {
+        self.map.select(n)
+    }
+
+    /// Return the pair at position `n` in order, with a mutable reference
+    /// to the value, or None if the map has `n` or fewer pairs.
+    pub fn nth_mut<'a>(&'a mut self, n: uint) -> Option<(&'a K, &'a mut V)> {
+        self.map.nth_mut(n)
+    }
+
+    /// Return the pair at the `p`th percentile of the keys, by the nearest
+    /// rank method, or None if the map is empty. Fails if `p` is not
+    /// between 0 and 100.
+    pub fn percentile<'a>(&'a self, p: f64) -> Option<(&'a K, &'a V)> {
+        self.map.percentile(p)
+    }
+
+    /// Return the key at the `p`th percentile when each key is counted
+    /// `weight(value)` times, as in a histogram of counts, or None if the
+    /// total weight is zero. Fails if `p` is not between 0 and 100.
+    pub fn weighted_percentile<'a>(&'a self, p: f64, weight: &fn(&V) -> uint)
+                                   -> Option<&'a K> {
+        self.map.weighted_percentile(p, weight)
+    }
+
+    /// Visit the differences which turn `self` into `other`, in order of
+    /// their keys.
+    pub fn diff<'a>(&'a self, other: &'a FlatMap<K, V>) -> FlatMapDiff<'a, K, V, NaturalOrder> {
+        self.map.diff(&other.map)
     }
 
     /// An iterator visiting all key-value pairs in order,
     /// with mutable references to the values.
     /// Iterator element type is (&'a K, &'a mut V).
     pub fn mut_iter<'a>(&'a mut self) -> FlatMapMutIterator<'a, K, V> {
-        FlatMapMutIterator{iter: self.data.mut_iter()}
+        self.map.mut_iter()
     }
 
     /// Creates a consuming iterator, that is, one that moves each key-value
     /// pair out of the map in reverse order. The map cannot be used after
     /// calling this.
     pub fn move_iter(self) -> FlatMapMoveIterator<K, V> {
-        // `move_rev_iter` is more efficient than `move_iter` for vectors
-        FlatMapMoveIterator {iter: self.data.move_rev_iter()}
+        self.map.move_iter()
+    }
+
+    /// An iterator visiting all key-value pairs in reverse order.
+    pub fn rev_iter<'a>(&'a self) -> Invert<FlatMapIterator<'a, K, V>> {
+        self.map.rev_iter()
+    }
+
+    /// An iterator visiting all key-value pairs in reverse order,
+    /// with mutable references to the values.
+    pub fn mut_rev_iter<'a>(&'a mut self) -> Invert<FlatMapMutIterator<'a, K, V>> {
+        self.map.mut_rev_iter()
+    }
+
+    /// A cursor on the first pair of the map.
+    pub fn cursor<'a>(&'a self) -> Cursor<'a, K, V, NaturalOrder> {
+        self.map.cursor()
+    }
+
+    /// A cursor on the first pair of the map which can modify the map.
+    pub fn cursor_mut<'a>(&'a mut self) -> CursorMut<'a, K, V, NaturalOrder> {
+        self.map.cursor_mut()
+    }
+
+    /// Convert into a read-only map with a faster search, for maps which
+    /// are queried much more often than they are modified.
+    pub fn freeze(self) -> FrozenFlatMap<K, V> {
+        self.map.freeze()
+    }
+}
+
+impl<K: TotalOrd + fmt::Default, V> FlatMap<K, V> {
+    /// Retrieves a value for the given key, failing if the key is not
+    /// present.
+    pub fn get<'a>(&'a self, k: &K) -> &'a V {
+        self.map.get(k)
+    }
+
+    /// Retrieves a (mutable) value for the given key, failing if the key
+    /// is not present.
+    pub fn get_mut<'a>(&'a mut self, k: &K) -> &'a mut V {
+        self.map.get_mut(k)
+    }
+}
+
+impl<K: Clone, V: Clone> Clone for FlatMap<K, V> {
+    fn clone(&self) -> FlatMap<K, V> {
+        FlatMap{map: self.map.clone()}
     }
 }
 
 impl<K, V> Container for FlatMap<K, V> {
     /// Return the number of elements in the map.
     fn len(&self) -> uint {
-        self.data.len()
+        self.map.len()
     }
 }
 
 impl<K, V> Mutable for FlatMap<K, V> {
     /// Clear the map, removing all items.
     fn clear(&mut self) {
-        self.data.clear();
+        self.map.clear();
     }
 }
 
 impl<K: TotalOrd, V> FromIterator<(K, V)> for FlatMap<K, V> {
     fn from_iterator<T: Iterator<(K, V)>>(iter: &mut T) -> FlatMap<K, V> {
-        let (lower, _) = iter.size_hint();
-        let mut map = FlatMap::with_capacity(lower);
-        map.extend(iter);
-        map
+        FlatMap::from_iter_with(iter, KeepLast)
     }
 }
 
 impl<K: TotalOrd, V> Extendable<(K, V)> for FlatMap<K, V> {
     fn extend<T: Iterator<(K, V)>>(&mut self, iter: &mut T) {
-        for (k, v) in *iter {
-            self.insert(k, v);
-        }
+        self.map.extend(iter);
     }
 }
 
@@ -187,410 +812,6662 @@ impl<K: TotalOrd, V> Default for FlatMap<K, V> {
     fn default() -> FlatMap<K, V> { FlatMap::new() }
 }
 
-impl<K: Eq + TotalOrd, V: Eq> Eq for FlatMap<K, V> {
-    fn eq(&self, other: &FlatMap<K, V>) -> bool {
-        self.len() == other.len() &&
-            self.iter().zip(other.iter()).all(|(a, b)| a == b)
-    }
+impl<K: TotalOrd, V: Eq> Eq for FlatMap<K, V> {
+    fn eq(&self, other: &FlatMap<K, V>) -> bool { self.map == other.map }
 }
 
-// Lexicographical comparison
-fn lt<K: Ord + TotalOrd, V: Ord>(a: &FlatMap<K, V>,
-                                 b: &FlatMap<K, V>) -> bool {
-    // the Zip iterator is as long as the shortest of a and b.
-    for ((key_a, value_a), (key_b, value_b)) in a.iter().zip(b.iter()) {
-        if *key_a < *key_b { return true; }
-        if *key_a > *key_b { return false; }
+impl<K: TotalOrd, V: Ord> Ord for FlatMap<K, V> {
+    #[inline]
+    fn lt(&self, other: &FlatMap<K, V>) -> bool { self.map < other.map }
+    #[inline]
+    fn le(&self, other: &FlatMap<K, V>) -> bool { self.map <= other.map }
+    #[inline]
+    fn ge(&self, other: &FlatMap<K, V>) -> bool { self.map >= other.map }
+    #[inline]
+    fn gt(&self, other: &FlatMap<K, V>) -> bool { self.map > other.map }
+}
+
+impl<K: TotalOrd, V: TotalEq> TotalEq for FlatMap<K, V> {
+    fn equals(&self, other: &FlatMap<K, V>) -> bool { self.map.equals(&other.map) }
+}
+
+impl<K: TotalOrd, V: TotalOrd> TotalOrd for FlatMap<K, V> {
+    fn cmp(&self, other: &FlatMap<K, V>) -> Ordering { self.map.cmp(&other.map) }
+}
+
+// Hashes the bytes of the keys, which agrees with Eq only when keys which
+// compare equal are identical, so only maps in natural order are hashable.
+impl<K: IterBytes, V: IterBytes> IterBytes for FlatMap<K, V> {
+    fn iter_bytes(&self, lsb0: bool, f: to_bytes::Cb) -> bool {
+        self.len().iter_bytes(lsb0, |b| f(b)) &&
+            self.iter().all(|(k, v)| {
+                k.iter_bytes(lsb0, |b| f(b)) && v.iter_bytes(lsb0, |b| f(b))
+            })
+    }
+}
+
+impl<K: fmt::Default, V: fmt::Default> fmt::Default for FlatMap<K, V> {
+    fn fmt(map: &FlatMap<K, V>, f: &mut fmt::Formatter) {
+        fmt::Default::fmt(&map.map, f)
+    }
+}
+
+impl<K: ToStr, V: ToStr> ToStr for FlatMap<K, V> {
+    fn to_str(&self) -> ~str {
+        self.map.to_str()
+    }
+}
+
+// Fails if the key is not present, like `get`. `Index` hands the value back
+// by value, so every `map[key]` clones it; borrow it with `get` or `find`
+// instead when that is costly.
+impl<K: TotalOrd + fmt::Default, V: Clone> Index<K, V> for FlatMap<K, V> {
+    fn index(&self, key: &K) -> V {
+        self.map.index(key)
+    }
+}
+
+impl<K: TotalOrd, V> Map<K, V> for FlatMap<K, V> {
+    fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        self.map.find(key)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+}
+
+impl<K: TotalOrd, V> MutableMap<K, V> for FlatMap<K, V> {
+    fn find_mut<'a>(&'a mut self, key: &K) -> Option<&'a mut V> {
+        self.map.find_mut(key)
+    }
+
+    fn swap(&mut self, k: K, v: V) -> Option<V> {
+        self.map.swap(k, v)
+    }
+
+    fn pop(&mut self, key: &K) -> Option<V> {
+        self.map.pop(key)
+    }
+}
+
+/// A flat map like FlatMap whose keys are ordered by the comparator `C`
+/// instead of their natural order.
+pub struct FlatMapBy<K, V, C> {
+    priv data: ~[(K, V)],
+    priv compare: C,
+}
+
+impl<K, V, C: Compare<K>> FlatMapBy<K, V, C> {
+    /// Create an empty FlatMapBy which orders its keys with `compare`.
+    pub fn with_comparator(compare: C) -> FlatMapBy<K, V, C> {
+        FlatMapBy::with_capacity_and_comparator(0, compare)
+    }
+
+    /// Create an empty FlatMapBy with space for at least `n` elements which
+    /// orders its keys with `compare`.
+    pub fn with_capacity_and_comparator(capacity: uint, compare: C)
+                                        -> FlatMapBy<K, V, C> {
+        FlatMapBy{data: vec::with_capacity(capacity), compare: compare}
+    }
+
+    /// Add the pairs of an iterator to the map in O((n + m) log m), where
+    /// `m` is the number of new pairs. Keys already in the map count as
+    /// seen first when resolving equal keys according to `dups`.
+    pub fn extend_with<T: Iterator<(K, V)>>(&mut self, iter: &mut T,
+                                            dups: Duplicates<K, V>) {
+        let data: ~[(K, V)] = iter.collect();
+        if data.is_empty() {
+            return;
+        }
+        let data = sort_by_key(data, |a, b| self.compare.compare(a, b));
+        self.merge_data(data, dups);
+    }
+
+    /// Combine two maps in O(n + m), calling `f` with the key and both
+    /// values, this map's first, for each key the maps have in common.
+    /// Both maps must order their keys the same way.
+    pub fn merge(self, other: FlatMapBy<K, V, C>, f: &fn(&K, V, V) -> V) -> FlatMapBy<K, V, C> {
+        let mut map = self;
+        map.merge_data(other.data, MergeWith(f));
+        map
+    }
+
+    /// Move all pairs of `other` into this map in O(n + m). Values from
+    /// `other` replace those of equal keys, as inserting each pair would.
+    /// Both maps must order their keys the same way.
+    pub fn append(&mut self, other: FlatMapBy<K, V, C>) {
+        self.merge_data(other.data, KeepLast);
+    }
+
+    fn merge_data(&mut self, data: ~[(K, V)], dups: Duplicates<K, V>) {
+        let old = replace(&mut self.data, ~[]);
+        let merged = {
+            let cmp = |a: &K, b: &K| self.compare.compare(a, b);
+            dedup_sorted(merge_runs(old, data, cmp), cmp, &dups)
+        };
+        self.data = merged;
+    }
+
+    /// Return the capacity of the underlying vector.
+    pub fn capacity(&self) -> uint {
+        self.data.capacity()
+    }
+
+    /// Keep only the pairs for which `f` returns true, in one pass.
+    pub fn retain(&mut self, f: &fn(&K, &V) -> bool) {
+        self.data.retain(|&(ref k, ref v)| f(k, v));
+    }
+
+    /// Remove the pairs whose key lies between `lo` and `hi` in O(n),
+    /// returning them in order.
+    pub fn drain_range(&mut self, lo: Bound<K>, hi: Bound<K>) -> ~[(K, V)] {
+        let (start, end) = self.range_indices(lo, hi);
+        let tail = self.split_tail(end);
+        let drained = self.split_tail(start);
+        self.data.push_all_move(tail);
+        drained
+    }
+
+    /// Split the map in two at `key`, leaving the pairs whose key is less
+    /// than `key` in this map and returning the rest.
+    pub fn split_off(&mut self, key: &K) -> FlatMapBy<K, V, C> {
+        let index = lower_bound_index(self.data, key, &self.compare);
+        FlatMapBy{data: self.split_tail(index), compare: self.compare.clone()}
+    }
+
+    /// Remove pairs from the front of the map, keeping the last `len`.
+    pub fn truncate_front(&mut self, len: uint) {
+        if len < self.data.len() {
+            let at = self.data.len() - len;
+            self.data = self.split_tail(at);
+        }
+    }
+
+    /// Remove pairs from the back of the map, keeping the first `len`.
+    pub fn truncate_back(&mut self, len: uint) {
+        self.data.truncate(len);
+    }
+
+    /// Collapse each run of keys for which `same` returns true when called
+    /// with the first key of the run into that first key, resolving the
+    /// values according to `dups`.
+    pub fn dedup_by(&mut self, same: &fn(&K, &K) -> bool, dups: Duplicates<K, V>) {
+        let data = replace(&mut self.data, ~[]);
+        self.data = dedup_sorted(data, |a, b| if same(a, b) { Equal } else { Less }, &dups);
+    }
+
+    /// Move the pairs from `at` onwards out of the map, in order.
+    fn split_tail(&mut self, at: uint) -> ~[(K, V)] {
+        let mut tail = vec::with_capacity(self.data.len() - at);
+        while self.data.len() > at {
+            tail.push(self.data.pop());
+        }
+        tail.reverse();
+        tail
+    }
+
+    /// Make room for at least `additional` more pairs, returning an error
+    /// instead of failing if memory runs out.
+    pub fn try_reserve(&mut self, additional: uint) -> Result<(), AllocError> {
+        try_reserve_vec(&mut self.data, additional)
+    }
+
+    /// Insert a key-value pair like `insert`, returning an error instead of
+    /// failing if memory runs out. Replacing the value of a key which is
+    /// already present never allocates.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<bool, AllocError> {
+        let index = lower_bound_index(self.data, &key, &self.compare);
+        if index < self.data.len() &&
+           self.compare.compare(key_at(self.data, index), &key) == Equal {
+            let (_, ref mut v) = self.data[index];
+            *v = value;
+            return Ok(false);
+        }
+        match self.try_reserve(1) {
+            Ok(()) => {
+                self.data.insert(index, (key, value));
+                Ok(true)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Release the spare capacity of the underlying vector.
+    pub fn shrink_to_fit(&mut self) {
+        self.data.shrink_to_fit();
+    }
+
+    /// Report how much memory the map holds for its pairs.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let size = sys::size_of::<(K, V)>();
+        MemoryUsage{used: self.data.len() * size, allocated: self.data.capacity() * size}
+    }
+
+    /// Get the entry for a key in the map for in-place manipulation. The key
+    /// is searched for once and the entry remembers where it was found.
+    pub fn entry<'a>(&'a mut self, key: K) -> Entry<'a, K, V, C> {
+        let index = lower_bound_index(self.data, &key, &self.compare);
+        if index < self.data.len() &&
+           self.compare.compare(key_at(self.data, index), &key) == Equal {
+            Occupied(OccupiedEntry{map: self, index: index})
+        } else {
+            Vacant(VacantEntry{map: self, key: key, index: index})
+        }
+    }
+
+    /// Modify and return the value corresponding to the key in the map, or
+    /// insert and return a new value if it doesn't exist.
+    pub fn mangle<'a,A>(&'a mut self, k: K, a: A, not_found: &fn(&K, A) -> V,
+                        found: &fn(&K, &mut V, A)) -> &'a mut V {
+        match self.entry(k) {
+            Occupied(e) => {
+                let (key, val) = e.into_pair_mut();
+                found(key, val, a);
+                val
+            }
+            Vacant(e) => {
+                let val = not_found(e.key(), a);
+                e.insert(val)
+            }
+        }
+    }
+
+    /// Return the value corresponding to the key in the map, or insert
+    /// and return the value if it doesn't exist.
+    pub fn find_or_insert<'a>(&'a mut self, k: K, v: V) -> &'a mut V {
+        self.mangle(k, v, |_k, a| a, |_k,_v,_a| ())
+    }
+
+    /// Return the value corresponding to the key in the map, or create,
+    /// insert, and return a new value if it doesn't exist.
+    pub fn find_or_insert_with<'a>(&'a mut self, k: K, f: &fn(&K) -> V)
+                               -> &'a mut V {
+        self.mangle(k, (), |k,_a| f(k), |_k,_v,_a| ())
+    }
+
+    /// Insert a key-value pair into the map if the key is not already present.
+    /// Otherwise, modify the existing value for the key.
+    /// Returns the new or modified value for the key.
+    pub fn insert_or_update_with<'a>(&'a mut self, k: K, v: V,
+                                     f: &fn(&K, &mut V)) -> &'a mut V {
+        self.mangle(k, v, |_k,a| a, |k,v,_a| f(k,v))
+    }
+
+    /// Return the value corresponding to the key in the map, using
+    /// equivalence. The map must be ordered consistently with `Q`'s
+    /// `equiv_cmp`, as maps in natural order are.
+    pub fn find_equiv<'a, Q: OrdEquiv<K>>(&'a self, key: &Q)
+                                             -> Option<&'a V> {
+        let index = lower_bound_by(self.data, |&(ref k, _)| key.equiv_cmp(k) == Greater);
+        if index < self.data.len() {
+            let (ref k, ref v) = self.data[index];
+            if key.equiv(k) {
+                return Some(v)
+            }
+        }
+        None
+    }
+
+    /// An iterator visiting all key-value pairs in order.
+    /// Iterator element type is (&'a K, &'a V).
+    pub fn iter<'a>(&'a self) -> FlatMapIterator<'a, K, V> {
+        FlatMapIterator{iter: self.data.iter()}
+    }
+
+    /// An iterator visiting the key-value pairs whose key is not less than
+    /// `key`, in order.
+    pub fn lower_bound<'a>(&'a self, key: &K) -> FlatMapIterator<'a, K, V> {
+        let index = lower_bound_index(self.data, key, &self.compare);
+        FlatMapIterator{iter: self.data.slice_from(index).iter()}
+    }
+
+    /// An iterator visiting the key-value pairs whose key is greater than
+    /// `key`, in order.
+    pub fn upper_bound<'a>(&'a self, key: &K) -> FlatMapIterator<'a, K, V> {
+        let index = upper_bound_index(self.data, key, &self.compare);
+        FlatMapIterator{iter: self.data.slice_from(index).iter()}
+    }
+
+    /// An iterator visiting the key-value pairs whose key lies between
+    /// `lo` and `hi`, in order.
+    pub fn range<'a>(&'a self, lo: Bound<K>, hi: Bound<K>)
+                     -> FlatMapIterator<'a, K, V> {
+        let (start, end) = self.range_indices(lo, hi);
+        FlatMapIterator{iter: self.data.slice(start, end).iter()}
+    }
+
+    fn range_indices(&self, lo: Bound<K>, hi: Bound<K>) -> (uint, uint) {
+        let start = match lo {
+            Included(k) => lower_bound_index(self.data, k, &self.compare),
+            Excluded(k) => upper_bound_index(self.data, k, &self.compare),
+            Unbounded => 0,
+        };
+        let end = match hi {
+            Included(k) => upper_bound_index(self.data, k, &self.compare),
+            Excluded(k) => lower_bound_index(self.data, k, &self.compare),
+            Unbounded => self.data.len(),
+        };
+        if end < start {
+            (start, start)
+        } else {
+            (start, end)
+        }
+    }
+
+    /// Return the number of keys in the map which are less than `key`.
+    pub fn rank(&self, key: &K) -> uint {
+        lower_bound_index(self.data, key, &self.compare)
+    }
+
+    /// Return the pair at position `n` in order, or None if the map has
+    /// `n` or fewer pairs.
+    pub fn select<'a>(&'a self, n: uint) -> Option<(&'a K, &'a V)> {
+        if n < self.data.len() {
+            let (ref k, ref v) = self.data[n];
+            Some((k, v))
+        } else {
+            None
+        }
+    }
+
+    /// Return the pair at position `n` in order, with a mutable reference
+    /// to the value, or None if the map has `n` or fewer pairs.
+    pub fn nth_mut<'a>(&'a mut self, n: uint) -> Option<(&'a K, &'a mut V)> {
+        if n < self.data.len() {
+            let (ref k, ref mut v) = self.data[n];
+            Some((k, v))
+        } else {
+            None
+        }
+    }
+
+    /// Return the pair at the `p`th percentile of the keys, by the nearest
+    /// rank method, or None if the map is empty. Fails if `p` is not
+    /// between 0 and 100.
+    pub fn percentile<'a>(&'a self, p: f64) -> Option<(&'a K, &'a V)> {
+        self.select(nearest_rank(p, self.data.len()))
+    }
+
+    /// Return the key at the `p`th percentile when each key is counted
+    /// `weight(value)` times, as in a histogram of counts, or None if the
+    /// total weight is zero. Fails if `p` is not between 0 and 100.
+    pub fn weighted_percentile<'a>(&'a self, p: f64, weight: &fn(&V) -> uint)
+                                   -> Option<&'a K> {
+        let total = self.data.iter().fold(0u, |sum, &(_, ref v)| sum + weight(v));
+        let target = nearest_rank(p, total);
+        let mut seen = 0u;
+        for &(ref k, ref v) in self.data.iter() {
+            seen += weight(v);
+            if seen > target {
+                return Some(k);
+            }
+        }
+        None
+    }
+
+    /// Visit the differences which turn `self` into `other`, in order of
+    /// their keys. Both maps must order their keys the same way.
+    pub fn diff<'a>(&'a self, other: &'a FlatMapBy<K, V, C>) -> FlatMapDiff<'a, K, V, C> {
+        FlatMapDiff{a: self.data.as_slice(), b: other.data.as_slice(), compare: &self.compare}
+    }
+
+    /// An iterator visiting all key-value pairs in order,
+    /// with mutable references to the values.
+    /// Iterator element type is (&'a K, &'a mut V).
+    pub fn mut_iter<'a>(&'a mut self) -> FlatMapMutIterator<'a, K, V> {
+        FlatMapMutIterator{iter: self.data.mut_iter()}
+    }
+
+    /// Creates a consuming iterator, that is, one that moves each key-value
+    /// pair out of the map in reverse order. The map cannot be used after
+    /// calling this.
+    pub fn move_iter(self) -> FlatMapMoveIterator<K, V> {
+        FlatMapMoveIterator::new(self.data)
+    }
+
+    /// An iterator visiting all key-value pairs in reverse order.
+    pub fn rev_iter<'a>(&'a self) -> Invert<FlatMapIterator<'a, K, V>> {
+        self.iter().invert()
+    }
+
+    /// An iterator visiting all key-value pairs in reverse order,
+    /// with mutable references to the values.
+    pub fn mut_rev_iter<'a>(&'a mut self) -> Invert<FlatMapMutIterator<'a, K, V>> {
+        self.mut_iter().invert()
+    }
+
+    /// A cursor on the first pair of the map.
+    pub fn cursor<'a>(&'a self) -> Cursor<'a, K, V, C> {
+        Cursor{data: self.data.as_slice(), index: 0, compare: &self.compare}
+    }
+
+    /// A cursor on the first pair of the map which can modify the map.
+    pub fn cursor_mut<'a>(&'a mut self) -> CursorMut<'a, K, V, C> {
+        CursorMut{map: self, index: 0}
+    }
+
+    /// Convert into a read-only map with a faster search, for maps which
+    /// are queried much more often than they are modified.
+    pub fn freeze(self) -> FrozenFlatMapBy<K, V, C> {
+        let FlatMapBy{data, compare} = self;
+        FrozenFlatMap::from_sorted(data, compare)
+    }
+
+    #[inline]
+    fn find_index(&self, key: &K) -> Option<uint> {
+        let index = lower_bound_index(self.data, key, &self.compare);
+        if index < self.data.len() && self.compare.compare(key_at(self.data, index), key) == Equal {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
+impl<K: fmt::Default, V, C: Compare<K>> FlatMapBy<K, V, C> {
+    /// Retrieves a value for the given key, failing if the key is not
+    /// present.
+    pub fn get<'a>(&'a self, k: &K) -> &'a V {
+        match self.find(k) {
+            Some(v) => v,
+            None => fail2!("No entry found for key: {}", k),
+        }
+    }
+
+    /// Retrieves a (mutable) value for the given key, failing if the key
+    /// is not present.
+    pub fn get_mut<'a>(&'a mut self, k: &K) -> &'a mut V {
+        match self.find_mut(k) {
+            Some(v) => v,
+            None => fail2!("No entry found for key: {}", k),
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, C: Compare<K>> Clone for FlatMapBy<K, V, C> {
+    fn clone(&self) -> FlatMapBy<K, V, C> {
+        FlatMapBy{data: self.data.clone(), compare: self.compare.clone()}
+    }
+}
+
+impl<K, V, C> Container for FlatMapBy<K, V, C> {
+    /// Return the number of elements in the map.
+    fn len(&self) -> uint {
+        self.data.len()
+    }
+}
+
+impl<K, V, C> Mutable for FlatMapBy<K, V, C> {
+    /// Clear the map, removing all items.
+    fn clear(&mut self) {
+        self.data.clear();
+    }
+}
+
+impl<K, V, C: Compare<K>> Extendable<(K, V)> for FlatMapBy<K, V, C> {
+    fn extend<T: Iterator<(K, V)>>(&mut self, iter: &mut T) {
+        self.extend_with(iter, KeepLast);
+    }
+}
+
+// Keys are compared with the comparator of `self`
+impl<K, V: Eq, C: Compare<K>> Eq for FlatMapBy<K, V, C> {
+    fn eq(&self, other: &FlatMapBy<K, V, C>) -> bool {
+        self.len() == other.len() &&
+            self.iter().zip(other.iter()).all(|((key_a, value_a), (key_b, value_b))| {
+                self.compare.compare(key_a, key_b) == Equal && *value_a == *value_b
+            })
+    }
+}
+
+// Lexicographical comparison, with keys compared by the comparator of `a`
+fn lt<K, V: Ord, C: Compare<K>>(a: &FlatMapBy<K, V, C>,
+                                b: &FlatMapBy<K, V, C>) -> bool {
+    // the Zip iterator is as long as the shortest of a and b.
+    for ((key_a, value_a), (key_b, value_b)) in a.iter().zip(b.iter()) {
+        match a.compare.compare(key_a, key_b) {
+            Less => return true,
+            Greater => return false,
+            Equal => {}
+        }
         if *value_a < *value_b { return true; }
         if *value_a > *value_b { return false; }
     }
 
-    a.len() < b.len()
-}
+    a.len() < b.len()
+}
+
+impl<K, V: Ord, C: Compare<K>> Ord for FlatMapBy<K, V, C> {
+    #[inline]
+    fn lt(&self, other: &FlatMapBy<K, V, C>) -> bool { lt(self, other) }
+    #[inline]
+    fn le(&self, other: &FlatMapBy<K, V, C>) -> bool { !lt(other, self) }
+    #[inline]
+    fn ge(&self, other: &FlatMapBy<K, V, C>) -> bool { !lt(self, other) }
+    #[inline]
+    fn gt(&self, other: &FlatMapBy<K, V, C>) -> bool { lt(other, self) }
+}
+
+impl<K, V: TotalEq, C: Compare<K>> TotalEq for FlatMapBy<K, V, C> {
+    fn equals(&self, other: &FlatMapBy<K, V, C>) -> bool {
+        self.len() == other.len() &&
+            self.iter().zip(other.iter()).all(|((key_a, value_a), (key_b, value_b))| {
+                self.compare.compare(key_a, key_b) == Equal && value_a.equals(value_b)
+            })
+    }
+}
+
+// Lexicographical comparison, with keys compared by the comparator of `self`
+impl<K, V: TotalOrd, C: Compare<K>> TotalOrd for FlatMapBy<K, V, C> {
+    fn cmp(&self, other: &FlatMapBy<K, V, C>) -> Ordering {
+        for ((key_a, value_a), (key_b, value_b)) in self.iter().zip(other.iter()) {
+            match self.compare.compare(key_a, key_b) {
+                Equal => {}
+                order => return order,
+            }
+            match value_a.cmp(value_b) {
+                Equal => {}
+                order => return order,
+            }
+        }
+        self.len().cmp(&other.len())
+    }
+}
+
+// Formats as `{k: v, ...}`
+impl<K: fmt::Default, V: fmt::Default, C> fmt::Default for FlatMapBy<K, V, C> {
+    fn fmt(map: &FlatMapBy<K, V, C>, f: &mut fmt::Formatter) {
+        write!(f.buf, "\\{");
+        for (i, (k, v)) in map.iter().enumerate() {
+            if i > 0 {
+                write!(f.buf, ", ");
+            }
+            write!(f.buf, "{}: {}", *k, *v);
+        }
+        write!(f.buf, "\\}");
+    }
+}
+
+impl<K: ToStr, V: ToStr, C> ToStr for FlatMapBy<K, V, C> {
+    fn to_str(&self) -> ~str {
+        let pairs = self.iter().map(|(k, v)| format!("{}: {}", k.to_str(), v.to_str()))
+                        .collect::<~[~str]>();
+        format!("\\{{}\\}", pairs.connect(", "))
+    }
+}
+
+// Fails if the key is not present, like `get`. `Index` hands the value back
+// by value, so every `map[key]` clones it; borrow it with `get` or `find`
+// instead when that is costly.
+impl<K: fmt::Default, V: Clone, C: Compare<K>> Index<K, V> for FlatMapBy<K, V, C> {
+    fn index(&self, key: &K) -> V {
+        self.get(key).clone()
+    }
+}
+
+impl<K, V, C: Compare<K>> Map<K, V> for FlatMapBy<K, V, C> {
+    fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        match self.find_index(key) {
+            None => None,
+            Some(idx) => {
+                let (_, ref v) = self.data[idx];
+                Some(v)
+            }
+        }
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        match self.find(key) {
+            Some(_) => true,
+            None => false
+        }
+    }
+}
+
+impl<K, V, C: Compare<K>> MutableMap<K, V> for FlatMapBy<K, V, C> {
+    fn find_mut<'a>(&'a mut self, key: &K) -> Option<&'a mut V> {
+        match self.find_index(key) {
+            None => None,
+            Some(idx) => {
+                let (_, ref mut v) = self.data[idx];
+                Some(v)
+            }
+        }
+    }
+
+    fn swap(&mut self, k: K, v: V) -> Option<V> {
+        match self.entry(k) {
+            Occupied(e) => {
+                let mut e = e;
+                Some(e.insert(v))
+            }
+            Vacant(e) => {
+                e.insert(v);
+                None
+            }
+        }
+    }
+
+    fn pop(&mut self, key: &K) -> Option<V> {
+        match self.find_index(key) {
+            None => None,
+            Some(idx) => {
+                let (_, v) = self.data.remove(idx);
+                Some(v)
+            }
+        }
+    }
+}
+
+/// A key whose position among other keys can be estimated from its value,
+/// for interpolation search. `ordinal` must not decrease as keys increase.
+pub trait InterpolationKey: TotalOrd {
+    /// Map the key onto an unsigned integer, preserving order.
+    fn ordinal(&self) -> u64;
+}
+
+macro_rules! unsigned_interpolation_key_impl {
+    ($($t:ty),+) => ($(
+        impl InterpolationKey for $t {
+            #[inline]
+            fn ordinal(&self) -> u64 { *self as u64 }
+        }
+    )+)
+}
+
+macro_rules! signed_interpolation_key_impl {
+    ($($t:ty),+) => ($(
+        impl InterpolationKey for $t {
+            // flipping the sign bit moves negative values below positive ones
+            #[inline]
+            fn ordinal(&self) -> u64 { (*self as i64 as u64) ^ (1u64 << 63) }
+        }
+    )+)
+}
+
+unsigned_interpolation_key_impl!{u8, u16, u32, u64, uint}
+signed_interpolation_key_impl!{i8, i16, i32, i64, int}
+
+/// Find the first pair in `a` whose key is not less than `key` by guessing
+/// its position from the keys at the ends of the remaining range. A guess
+/// which fails to halve the range is followed by a bisection, so skewed
+/// keys cost at most twice as many probes as a binary search while uniform
+/// keys take O(log log n).
+fn interpolation_lower_bound<K: InterpolationKey, V>(a: &[(K, V)], key: &K) -> uint {
+    let target = key.ordinal();
+    // the bound lies in [lo, hi]
+    let mut lo = 0u;
+    let mut hi = a.len();
+    let mut bisect = false;
+    while lo < hi {
+        if key.cmp(key_at(a, lo)) != Greater {
+            return lo;
+        }
+        if key.cmp(key_at(a, hi - 1)) == Greater {
+            return hi;
+        }
+        // the first key is less than `key` and the last is not, so there
+        // are at least two pairs left and the bound is in (lo, hi - 1]
+        let width = hi - lo;
+        let first = key_at(a, lo).ordinal();
+        let last = key_at(a, hi - 1).ordinal();
+        let mid = if bisect || first == last {
+            lo + width / 2
+        } else {
+            let fraction = (target - first) as f64 / (last - first) as f64;
+            cmp::min(lo + 1 + (fraction * (width - 2) as f64) as uint, hi - 1)
+        };
+        if key_at(a, mid).cmp(key) == Less {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+        bisect = !bisect && hi - lo > width / 2;
+    }
+    lo
+}
+
+/// The natural order of keys, searched by interpolation instead of
+/// bisection. A map created with `FlatMapBy::with_comparator(InterpolationSearch)`
+/// uses it for `find`, `contains_key`, `entry`, `insert`, `pop` and the bound
+/// queries, which is faster than a binary search when the keys are spread
+/// evenly and never much slower.
+#[deriving(Clone, Eq)]
+pub struct InterpolationSearch;
+
+impl<K: InterpolationKey> Compare<K> for InterpolationSearch {
+    #[inline]
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        a.cmp(b)
+    }
+
+    #[inline]
+    fn lower_bound<V>(&self, a: &[(K, V)], key: &K) -> uint {
+        interpolation_lower_bound(a, key)
+    }
+}
+
+// Keys which share a prefix are contiguous only in the natural order of
+// strings, so only maps in that order can be searched by prefix.
+impl<V> FlatMap<~str, V> {
+    /// An iterator visiting the key-value pairs whose key starts with
+    /// `prefix`, in order. Both ends of the run of matching keys are found
+    /// by binary search.
+    pub fn prefix_iter<'a>(&'a self, prefix: &str) -> FlatMapIterator<'a, ~str, V> {
+        let start = lower_bound_by(self.map.data, |&(ref k, _)| k.as_slice() < prefix);
+        let rest = self.map.data.slice_from(start);
+        let len = lower_bound_by(rest, |&(ref k, _)| k.starts_with(prefix));
+        FlatMapIterator{iter: rest.slice_to(len).iter()}
+    }
+
+    /// Return the pair with the longest key which is a prefix of `key`.
+    pub fn longest_prefix_match<'a>(&'a self, key: &str) -> Option<(&'a ~str, &'a V)> {
+        let mut query = key;
+        loop {
+            // find the greatest key which is not greater than `query`.
+            // Every key which is a prefix of `query` lies between that key
+            // and `query`, so it is a prefix of that key too
+            let index = lower_bound_by(self.map.data, |&(ref k, _)| k.as_slice() <= query);
+            if index == 0 {
+                return None;
+            }
+            let (ref k, ref v) = self.map.data[index - 1];
+            if query.starts_with(*k) {
+                return Some((k, v));
+            }
+            // `k` is less than `query` and not a prefix of it, so they
+            // differ before the end of `query`
+            let (a, b) = (query.as_bytes(), k.as_bytes());
+            let mut common = 0;
+            while common < b.len() && a[common] == b[common] {
+                common += 1;
+            }
+            while !query.is_char_boundary(common) {
+                common -= 1;
+            }
+            query = query.slice_to(common);
+        }
+    }
+}
+
+/// A view into a single key of a FlatMap, which is either occupied or vacant.
+pub enum Entry<'self, K, V, C> {
+    /// The key is in the map.
+    Occupied(OccupiedEntry<'self, K, V, C>),
+    /// The key is not in the map.
+    Vacant(VacantEntry<'self, K, V, C>),
+}
+
+/// An entry for a key that is in the map, holding the index of its pair.
+pub struct OccupiedEntry<'self, K, V, C> {
+    priv map: &'self mut FlatMapBy<K, V, C>,
+    priv index: uint,
+}
+
+/// An entry for a key that is not in the map, holding the index its pair
+/// would be inserted at.
+pub struct VacantEntry<'self, K, V, C> {
+    priv map: &'self mut FlatMapBy<K, V, C>,
+    priv key: K,
+    priv index: uint,
+}
+
+impl<'self, K, V, C> Entry<'self, K, V, C> {
+    /// Return the value of the entry, inserting `default` if it is vacant.
+    pub fn or_insert(self, default: V) -> &'self mut V {
+        match self {
+            Occupied(e) => e.into_mut(),
+            Vacant(e) => e.insert(default),
+        }
+    }
+
+    /// Return the value of the entry, inserting the result of `f` if it is
+    /// vacant.
+    pub fn or_insert_with(self, f: &fn(&K) -> V) -> &'self mut V {
+        match self {
+            Occupied(e) => e.into_mut(),
+            Vacant(e) => {
+                let v = f(e.key());
+                e.insert(v)
+            }
+        }
+    }
+
+    /// Modify the value of an occupied entry in place. A vacant entry is
+    /// returned unchanged.
+    pub fn and_modify(self, f: &fn(&K, &mut V)) -> Entry<'self, K, V, C> {
+        match self {
+            Occupied(e) => {
+                let mut e = e;
+                {
+                    let (k, v) = e.pair_mut();
+                    f(k, v);
+                }
+                Occupied(e)
+            }
+            Vacant(e) => Vacant(e),
+        }
+    }
+}
+
+impl<'self, K, V, C> OccupiedEntry<'self, K, V, C> {
+    /// Return the key of the entry.
+    pub fn key<'a>(&'a self) -> &'a K {
+        key_at(self.map.data, self.index)
+    }
+
+    /// Return the value of the entry.
+    pub fn get<'a>(&'a self) -> &'a V {
+        let (_, ref v) = self.map.data[self.index];
+        v
+    }
+
+    /// Return the (mutable) value of the entry.
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut V {
+        let (_, ref mut v) = self.map.data[self.index];
+        v
+    }
+
+    /// Convert the entry into a reference to its value with the lifetime of
+    /// the map borrow.
+    pub fn into_mut(self) -> &'self mut V {
+        let (_, v) = self.into_pair_mut();
+        v
+    }
+
+    /// Replace the value of the entry, returning the old value.
+    pub fn insert(&mut self, value: V) -> V {
+        replace(self.get_mut(), value)
+    }
+
+    /// Remove the entry from the map, returning its value.
+    pub fn remove(self) -> V {
+        let OccupiedEntry{map, index} = self;
+        let (_, v) = map.data.remove(index);
+        v
+    }
+
+    fn pair_mut<'a>(&'a mut self) -> (&'a K, &'a mut V) {
+        let (ref k, ref mut v) = self.map.data[self.index];
+        (k, v)
+    }
+
+    fn into_pair_mut(self) -> (&'self K, &'self mut V) {
+        let OccupiedEntry{map, index} = self;
+        let (ref k, ref mut v) = map.data[index];
+        (k, v)
+    }
+}
+
+impl<'self, K, V, C> VacantEntry<'self, K, V, C> {
+    /// Return the key the entry was created with.
+    pub fn key<'a>(&'a self) -> &'a K {
+        &self.key
+    }
+
+    /// Insert a value for the key at the position found by the search,
+    /// returning a reference to it.
+    pub fn insert(self, value: V) -> &'self mut V {
+        let VacantEntry{map, key, index} = self;
+        map.data.insert(index, (key, value));
+        let (_, ref mut v) = map.data[index];
+        v
+    }
+}
+
+/// FlatMap iterator
+pub struct FlatMapIterator<'self, K, V> {
+    priv iter: vec::VecIterator<'self, (K, V)>,
+}
+
+impl<'self, K, V> Iterator<(&'self K, &'self V)> for FlatMapIterator<'self, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<(&'self K, &'self V)> {
+        match self.iter.next() {
+            Some(&(ref k, ref v)) => Some((k, v)),
+            None => None
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        return self.iter.size_hint();
+    }
+}
+
+impl<'self, K, V> DoubleEndedIterator<(&'self K, &'self V)> for FlatMapIterator<'self, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<(&'self K, &'self V)> {
+        match self.iter.next_back() {
+            Some(&(ref k, ref v)) => Some((k, v)),
+            None => None
+        }
+    }
+}
+
+/// FlatMap mutable values iterator
+pub struct FlatMapMutIterator<'self, K, V> {
+    priv iter: vec::VecMutIterator<'self, (K, V)>,
+}
+
+impl<'self, K, V> Iterator<(&'self K, &'self mut V)> for FlatMapMutIterator<'self, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<(&'self K, &'self mut V)> {
+        match self.iter.next() {
+            Some(&(ref k, ref mut v)) => Some((k, v)),
+            None => None
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        return self.iter.size_hint();
+    }
+}
+
+impl<'self, K, V> DoubleEndedIterator<(&'self K, &'self mut V)> for FlatMapMutIterator<'self, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<(&'self K, &'self mut V)> {
+        match self.iter.next_back() {
+            Some(&(ref k, ref mut v)) => Some((k, v)),
+            None => None
+        }
+    }
+}
+
+/// FlatMap move iterator. It yields the pairs in reverse order, so
+/// `next_back` yields them in order.
+pub struct FlatMapMoveIterator<K, V> {
+    // the length is set to zero, so the vector only owns the buffer and
+    // the pairs between `front` and `back` are owned by the iterator
+    priv data: ~[(K, V)],
+    priv front: uint,
+    priv back: uint,
+}
+
+impl<K, V> FlatMapMoveIterator<K, V> {
+    fn new(data: ~[(K, V)]) -> FlatMapMoveIterator<K, V> {
+        let mut data = data;
+        let len = data.len();
+        unsafe {
+            vec::raw::set_len(&mut data, 0);
+        }
+        FlatMapMoveIterator{data: data, front: 0, back: len}
+    }
+
+    #[inline]
+    unsafe fn read(&self, i: uint) -> (K, V) {
+        ptr::read_ptr(ptr::offset(vec::raw::to_ptr(self.data), i as int))
+    }
+}
+
+impl<K, V> Iterator<(K, V)> for FlatMapMoveIterator<K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<(K, V)> {
+        if self.front == self.back {
+            return None;
+        }
+        self.back -= 1;
+        unsafe {
+            Some(self.read(self.back))
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        let n = self.back - self.front;
+        (n, Some(n))
+    }
+}
+
+impl<K, V> DoubleEndedIterator<(K, V)> for FlatMapMoveIterator<K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<(K, V)> {
+        if self.front == self.back {
+            return None;
+        }
+        self.front += 1;
+        unsafe {
+            Some(self.read(self.front - 1))
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<K, V> Drop for FlatMapMoveIterator<K, V> {
+    fn drop(&mut self) {
+        // drop the pairs which were not moved out, then `data` frees the
+        // buffer
+        loop {
+            match self.next() {
+                None => break,
+                Some(_) => (),
+            }
+        }
+    }
+}
+
+/// Find the first pair in `a` whose key is not less than `key`, starting
+/// from position `from`. Searching forward gallops, so the cost grows with
+/// the log of the distance moved rather than the size of the map.
+fn seek_index<K, V, C: Compare<K>>(a: &[(K, V)], from: uint, key: &K, compare: &C) -> uint {
+    seek_by(a, from, |&(ref k, _)| compare.compare(k, key) == Less)
+}
+
+/// Find the first element of `a` for which `less` returns false, like
+/// `lower_bound_by`, galloping forward from position `from`.
+fn seek_by<T>(a: &[T], from: uint, less: &fn(&T) -> bool) -> uint {
+    if from > 0 && !less(&a[from - 1]) {
+        return lower_bound_by(a.slice_to(from), less);
+    }
+    // every pair in [from, lo) is less than `key`
+    let mut lo = from;
+    let mut step = 1u;
+    while lo + step <= a.len() && less(&a[lo + step - 1]) {
+        lo += step;
+        step *= 2;
+    }
+    let hi = cmp::min(lo + step, a.len());
+    lo + lower_bound_by(a.slice(lo, hi), less)
+}
+
+/// A read-only cursor over a FlatMap. The cursor is either on a pair or
+/// just past the last pair.
+pub struct Cursor<'self, K, V, C> {
+    priv data: &'self [(K, V)],
+    priv index: uint,
+    priv compare: &'self C,
+}
+
+impl<'self, K, V, C: Compare<K>> Cursor<'self, K, V, C> {
+    /// Return the pair the cursor is on, or None if it is past the end.
+    pub fn get(&self) -> Option<(&'self K, &'self V)> {
+        if self.index < self.data.len() {
+            let (ref k, ref v) = self.data[self.index];
+            Some((k, v))
+        } else {
+            None
+        }
+    }
+
+    /// Return the position of the cursor, which is the number of pairs
+    /// before it.
+    pub fn index(&self) -> uint {
+        self.index
+    }
+
+    /// Move to the next pair, returning false if the cursor was already
+    /// past the end.
+    pub fn move_next(&mut self) -> bool {
+        if self.index < self.data.len() {
+            self.index += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Move to the previous pair, returning false if the cursor was
+    /// already on the first pair.
+    pub fn move_prev(&mut self) -> bool {
+        if self.index > 0 {
+            self.index -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Move to the first pair whose key is not less than `key`, returning
+    /// true if its key is equal to `key`.
+    pub fn seek(&mut self, key: &K) -> bool {
+        self.index = seek_index(self.data, self.index, key, self.compare);
+        self.index < self.data.len() &&
+            self.compare.compare(key_at(self.data, self.index), key) == Equal
+    }
+}
+
+/// A cursor over a FlatMap which can modify values and insert or remove
+/// pairs at its position. The cursor is either on a pair or just past the
+/// last pair.
+pub struct CursorMut<'self, K, V, C> {
+    priv map: &'self mut FlatMapBy<K, V, C>,
+    priv index: uint,
+}
+
+impl<'self, K, V, C: Compare<K>> CursorMut<'self, K, V, C> {
+    /// Return the pair the cursor is on, or None if it is past the end.
+    pub fn get<'a>(&'a self) -> Option<(&'a K, &'a V)> {
+        if self.index < self.map.data.len() {
+            let (ref k, ref v) = self.map.data[self.index];
+            Some((k, v))
+        } else {
+            None
+        }
+    }
+
+    /// Return the pair the cursor is on with a mutable reference to the
+    /// value, or None if it is past the end.
+    pub fn get_mut<'a>(&'a mut self) -> Option<(&'a K, &'a mut V)> {
+        if self.index < self.map.data.len() {
+            let (ref k, ref mut v) = self.map.data[self.index];
+            Some((k, v))
+        } else {
+            None
+        }
+    }
+
+    /// Return the position of the cursor, which is the number of pairs
+    /// before it.
+    pub fn index(&self) -> uint {
+        self.index
+    }
+
+    /// Move to the next pair, returning false if the cursor was already
+    /// past the end.
+    pub fn move_next(&mut self) -> bool {
+        if self.index < self.map.data.len() {
+            self.index += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Move to the previous pair, returning false if the cursor was
+    /// already on the first pair.
+    pub fn move_prev(&mut self) -> bool {
+        if self.index > 0 {
+            self.index -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Move to the first pair whose key is not less than `key`, returning
+    /// true if its key is equal to `key`.
+    pub fn seek(&mut self, key: &K) -> bool {
+        self.index = seek_index(self.map.data, self.index, key, &self.map.compare);
+        self.index < self.map.data.len() &&
+            self.map.compare.compare(key_at(self.map.data, self.index), key) == Equal
+    }
+
+    /// Insert a pair at the position of the cursor, leaving the cursor on
+    /// it. Fails if the key does not sort strictly between the keys of the
+    /// pairs before and after the cursor.
+    pub fn insert(&mut self, key: K, value: V) {
+        let index = self.index;
+        let compare = &self.map.compare;
+        let data = &mut self.map.data;
+        if (index > 0 && compare.compare(key_at(*data, index - 1), &key) != Less) ||
+           (index < data.len() && compare.compare(&key, key_at(*data, index)) != Less) {
+            fail2!("CursorMut::insert: key is out of order at index {}", index);
+        }
+        data.insert(index, (key, value));
+    }
+
+    /// Remove and return the pair the cursor is on, leaving the cursor on
+    /// the next pair, or None if it is past the end.
+    pub fn remove(&mut self) -> Option<(K, V)> {
+        if self.index < self.map.data.len() {
+            Some(self.map.data.remove(self.index))
+        } else {
+            None
+        }
+    }
+}
+
+/// A difference between two maps, as produced by `FlatMap::diff`.
+#[deriving(Eq)]
+pub enum Diff<'self, K, V> {
+    /// The key is only in the new map.
+    Added(&'self K, &'self V),
+    /// The key is only in the old map.
+    Removed(&'self K, &'self V),
+    /// The key is in both maps with different values, old then new.
+    Changed(&'self K, &'self V, &'self V),
+}
+
+/// Lazy iterator producing the differences between two maps (in-order)
+pub struct FlatMapDiff<'self, K, V, C> {
+    priv a: &'self [(K, V)],
+    priv b: &'self [(K, V)],
+    priv compare: &'self C,
+}
+
+impl<'self, K, V: Eq, C: Compare<K>> Iterator<Diff<'self, K, V>> for FlatMapDiff<'self, K, V, C> {
+    fn next(&mut self) -> Option<Diff<'self, K, V>> {
+        loop {
+            let order = match (self.a.is_empty(), self.b.is_empty()) {
+                (true, true) => return None,
+                (false, true) => Less,
+                (true, false) => Greater,
+                (false, false) => self.compare.compare(key_at(self.a, 0), key_at(self.b, 0)),
+            };
+            match order {
+                Less => {
+                    let &(ref k, ref v) = shift_slice(&mut self.a);
+                    return Some(Removed(k, v));
+                }
+                Greater => {
+                    let &(ref k, ref v) = shift_slice(&mut self.b);
+                    return Some(Added(k, v));
+                }
+                Equal => {
+                    let &(ref k, ref old) = shift_slice(&mut self.a);
+                    let &(_, ref new) = shift_slice(&mut self.b);
+                    if *old != *new {
+                        return Some(Changed(k, old, new));
+                    }
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (0, Some(self.a.len() + self.b.len()))
+    }
+}
+
+/// Visit every pair of `maps` in key order without building their union.
+/// Pairs with equal keys come in the order of their maps. The pairs are
+/// merged in the natural order of their keys, so only maps in that order
+/// are accepted.
+pub fn merge_all_iter<'a, K: TotalOrd, V>(maps: &[&'a FlatMap<K, V>])
+                                      -> FlatMapMergeAllIterator<'a, K, V> {
+    FlatMapMergeAllIterator::new(maps.iter().map(|m| m.map.data.as_slice()).collect())
+}
+
+/// Visit each distinct key of `maps` in key order with its value. Values
+/// for a key found in several maps are resolved according to `dups`, with
+/// earlier maps counting as seen first, so `KeepLast` reads a stack of
+/// segments where later ones override earlier ones.
+pub fn merge_iter<'a, K: TotalOrd, V: Clone>(maps: &[&'a FlatMap<K, V>],
+                                          dups: Duplicates<'a, K, V>)
+                                          -> FlatMapMergeIterator<'a, K, V> {
+    FlatMapMergeIterator{iter: merge_all_iter(maps), pending: None, dups: dups}
+}
+
+/// Lazy iterator producing every pair of several maps (in-order)
+pub struct FlatMapMergeAllIterator<'self, K, V> {
+    // the unvisited pairs of each map
+    priv heads: ~[&'self [(K, V)]],
+    // the maps with pairs left, as a min-heap on their next key
+    priv heap: ~[uint],
+}
+
+impl<'self, K: TotalOrd, V> FlatMapMergeAllIterator<'self, K, V> {
+    fn new(heads: ~[&'self [(K, V)]]) -> FlatMapMergeAllIterator<'self, K, V> {
+        let heap = range(0, heads.len()).filter(|&i| !heads[i].is_empty()).collect();
+        let mut iter = FlatMapMergeAllIterator{heads: heads, heap: heap};
+        let mut i = iter.heap.len() / 2;
+        while i > 0 {
+            i -= 1;
+            iter.sift_down(i);
+        }
+        iter
+    }
+
+    #[inline]
+    fn heap_less(&self, a: uint, b: uint) -> bool {
+        let (a, b) = (self.heap[a], self.heap[b]);
+        match key_at(self.heads[a], 0).cmp(key_at(self.heads[b], 0)) {
+            Less => true,
+            Greater => false,
+            Equal => a < b,
+        }
+    }
+
+    fn sift_down(&mut self, i: uint) {
+        let mut i = i;
+        loop {
+            let mut least = i;
+            for child in range(2 * i + 1, cmp::min(2 * i + 3, self.heap.len())) {
+                if self.heap_less(child, least) {
+                    least = child;
+                }
+            }
+            if least == i {
+                return;
+            }
+            self.heap.swap(i, least);
+            i = least;
+        }
+    }
+}
+
+impl<'self, K: TotalOrd, V> Iterator<(&'self K, &'self V)> for FlatMapMergeAllIterator<'self, K, V> {
+    fn next(&mut self) -> Option<(&'self K, &'self V)> {
+        if self.heap.is_empty() {
+            return None;
+        }
+        let top = self.heap[0];
+        let &(ref k, ref v) = shift_slice(&mut self.heads[top]);
+        if self.heads[top].is_empty() {
+            self.heap.swap_remove(0);
+        }
+        self.sift_down(0);
+        Some((k, v))
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        let n = self.heads.iter().fold(0u, |n, h| n + h.len());
+        (n, Some(n))
+    }
+}
+
+/// Lazy iterator producing the distinct keys of several maps (in-order)
+pub struct FlatMapMergeIterator<'self, K, V> {
+    priv iter: FlatMapMergeAllIterator<'self, K, V>,
+    priv pending: Option<(&'self K, &'self V)>,
+    priv dups: Duplicates<'self, K, V>,
+}
+
+impl<'self, K: TotalOrd, V: Clone> Iterator<(&'self K, V)> for FlatMapMergeIterator<'self, K, V> {
+    fn next(&mut self) -> Option<(&'self K, V)> {
+        let (k, v) = match replace(&mut self.pending, None) {
+            Some(pair) => pair,
+            None => match self.iter.next() {
+                Some(pair) => pair,
+                None => return None,
+            },
+        };
+        // values are only copied once it is known which one is wanted
+        let mut latest = v;
+        let mut merged = None;
+        loop {
+            match self.iter.next() {
+                Some((k2, v2)) if k2.cmp(k) == Equal => {
+                    match self.dups {
+                        KeepFirst => (),
+                        KeepLast => latest = v2,
+                        MergeWith(ref f) => {
+                            let acc = match replace(&mut merged, None) {
+                                Some(acc) => acc,
+                                None => latest.clone(),
+                            };
+                            merged = Some((*f)(k, acc, v2.clone()));
+                        }
+                    }
+                }
+                next => {
+                    self.pending = next;
+                    break;
+                }
+            }
+        }
+        match merged {
+            Some(value) => Some((k, value)),
+            None => Some((k, latest.clone())),
+        }
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        let (lower, _) = self.iter.size_hint();
+        let pending = if self.pending.is_some() { 1 } else { 0 };
+        let n = lower + pending;
+        (if n > 0 { 1 } else { 0 }, Some(n))
+    }
+}
+
+/// A flat set implementation which stores its elements in a sorted
+/// vector ~[K].
+#[deriving(Clone, Eq, Ord)]
+pub struct FlatSet<K> {
+    priv data: ~[K],
+}
+
+impl<K: TotalOrd> FlatSet<K> {
+    /// Creates an empty FlatSet.
+    pub fn new() -> FlatSet<K> {
+        FlatSet::with_capacity(0)
+    }
+
+    /// Create an empty FlatSet with space for at least `n` elements.
+    pub fn with_capacity(capacity: uint) -> FlatSet<K> {
+        FlatSet{data: vec::with_capacity(capacity)}
+    }
+
+    /// Return the capacity of the underlying vector.
+    pub fn capacity(&self) -> uint {
+        self.data.capacity()
+    }
+
+    /// An iterator visiting all elements in order.
+    pub fn iter<'a>(&'a self) -> FlatSetIterator<'a, K> {
+        FlatSetIterator{iter: self.data.iter()}
+    }
+
+    /// Creates a consuming iterator, that is, one that moves each element
+    /// out of the set in order. The set cannot be used after calling this.
+    pub fn move_iter(self) -> FlatSetMoveIterator<K> {
+        FlatSetMoveIterator{iter: self.data.move_iter()}
+    }
+
+    /// Visit the elements in `self` that are not in `other`, in order.
+    pub fn difference<'a>(&'a self, other: &'a FlatSet<K>)
+                          -> FlatSetDifference<'a, K> {
+        FlatSetDifference{a: self.data.as_slice(), b: other.data.as_slice()}
+    }
+
+    /// Visit the elements in exactly one of `self` and `other`, in order.
+    pub fn symmetric_difference<'a>(&'a self, other: &'a FlatSet<K>)
+                                    -> FlatSetSymDifference<'a, K> {
+        FlatSetSymDifference{a: self.data.as_slice(), b: other.data.as_slice()}
+    }
+
+    /// Visit the elements in both `self` and `other`, in order.
+    pub fn intersection<'a>(&'a self, other: &'a FlatSet<K>)
+                            -> FlatSetIntersection<'a, K> {
+        FlatSetIntersection{a: self.data.as_slice(), b: other.data.as_slice()}
+    }
+
+    /// Visit the elements in `self` or `other` without duplicates, in order.
+    pub fn union<'a>(&'a self, other: &'a FlatSet<K>) -> FlatSetUnion<'a, K> {
+        FlatSetUnion{a: self.data.as_slice(), b: other.data.as_slice()}
+    }
+
+    fn from_map(map: FlatMap<K, ()>) -> FlatSet<K> {
+        FlatSet{data: map.into_data().move_iter().map(|(k, _)| k).collect()}
+    }
+}
+
+impl<K> Container for FlatSet<K> {
+    /// Return the number of elements in the set.
+    fn len(&self) -> uint {
+        self.data.len()
+    }
+}
+
+impl<K> Mutable for FlatSet<K> {
+    /// Clear the set, removing all elements.
+    fn clear(&mut self) {
+        self.data.clear();
+    }
+}
+
+impl<K: TotalOrd> Set<K> for FlatSet<K> {
+    fn contains(&self, value: &K) -> bool {
+        self.data.bsearch(|k| k.cmp(value)).is_some()
+    }
+
+    fn is_disjoint(&self, other: &FlatSet<K>) -> bool {
+        self.intersection(other).next().is_none()
+    }
+
+    fn is_subset(&self, other: &FlatSet<K>) -> bool {
+        self.difference(other).next().is_none()
+    }
+
+    fn is_superset(&self, other: &FlatSet<K>) -> bool {
+        other.is_subset(self)
+    }
+}
+
+impl<K: TotalOrd> MutableSet<K> for FlatSet<K> {
+    fn insert(&mut self, value: K) -> bool {
+        let index = keys_lower_bound_index(self.data, &value);
+        if index < self.data.len() && self.data[index].cmp(&value) == Equal {
+            return false;
+        }
+        self.data.insert(index, value);
+        true
+    }
+
+    fn remove(&mut self, value: &K) -> bool {
+        match self.data.bsearch(|k| k.cmp(value)) {
+            Some(index) => {
+                self.data.remove(index);
+                true
+            }
+            None => false
+        }
+    }
+}
+
+// Sorts and dedups in bulk through a FlatMap with no values, keeping the
+// first of equal elements as repeated `insert` calls would
+impl<K: TotalOrd> FromIterator<K> for FlatSet<K> {
+    fn from_iterator<T: Iterator<K>>(iter: &mut T) -> FlatSet<K> {
+        FlatSet::from_map(FlatMap::from_iter_with(&mut iter.map(|k| (k, ())), KeepFirst))
+    }
+}
+
+impl<K: TotalOrd> Extendable<K> for FlatSet<K> {
+    fn extend<T: Iterator<K>>(&mut self, iter: &mut T) {
+        let data = replace(&mut self.data, ~[]);
+        let mut map = FlatMap::from_data(data.move_iter().map(|k| (k, ())).collect());
+        map.extend_with(&mut iter.map(|k| (k, ())), KeepFirst);
+        *self = FlatSet::from_map(map);
+    }
+}
+
+impl<K: TotalOrd> Default for FlatSet<K> {
+    fn default() -> FlatSet<K> { FlatSet::new() }
+}
+
+/// FlatSet iterator
+pub struct FlatSetIterator<'self, K> {
+    priv iter: vec::VecIterator<'self, K>,
+}
+
+impl<'self, K> Iterator<&'self K> for FlatSetIterator<'self, K> {
+    #[inline]
+    fn next(&mut self) -> Option<&'self K> {
+        self.iter.next()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        return self.iter.size_hint();
+    }
+}
+
+/// FlatSet move iterator
+pub struct FlatSetMoveIterator<K> {
+    priv iter: vec::MoveIterator<K>,
+}
+
+impl<K> Iterator<K> for FlatSetMoveIterator<K> {
+    #[inline]
+    fn next(&mut self) -> Option<K> {
+        self.iter.next()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        return self.iter.size_hint();
+    }
+}
+
+/// Lazy iterator producing elements in the set difference (in-order)
+pub struct FlatSetDifference<'self, K> {
+    priv a: &'self [K],
+    priv b: &'self [K],
+}
+
+/// Lazy iterator producing elements in the set symmetric difference (in-order)
+pub struct FlatSetSymDifference<'self, K> {
+    priv a: &'self [K],
+    priv b: &'self [K],
+}
+
+/// Lazy iterator producing elements in the set intersection (in-order)
+pub struct FlatSetIntersection<'self, K> {
+    priv a: &'self [K],
+    priv b: &'self [K],
+}
+
+/// Lazy iterator producing elements in the set union (in-order)
+pub struct FlatSetUnion<'self, K> {
+    priv a: &'self [K],
+    priv b: &'self [K],
+}
+
+/// Remove and return the first element of a non-empty slice.
+#[inline]
+fn shift_slice<'a, K>(s: &mut &'a [K]) -> &'a K {
+    let v: &'a [K] = *s;
+    *s = v.slice_from(1);
+    &v[0]
+}
+
+/// Compare the heads of two sorted slices, returning `short` when `a` is
+/// exhausted and `long` when `b` is exhausted.
+#[inline]
+fn cmp_heads<K: TotalOrd>(a: &[K], b: &[K],
+                          short: Ordering, long: Ordering) -> Ordering {
+    if a.is_empty() {
+        short
+    } else if b.is_empty() {
+        long
+    } else {
+        a[0].cmp(&b[0])
+    }
+}
+
+impl<'self, K: TotalOrd> Iterator<&'self K> for FlatSetDifference<'self, K> {
+    fn next(&mut self) -> Option<&'self K> {
+        loop {
+            if self.a.is_empty() {
+                return None;
+            }
+            match cmp_heads(self.a, self.b, Less, Less) {
+                Less => return Some(shift_slice(&mut self.a)),
+                Equal => {
+                    shift_slice(&mut self.a);
+                    shift_slice(&mut self.b);
+                }
+                Greater => { shift_slice(&mut self.b); }
+            }
+        }
+    }
+}
+
+impl<'self, K: TotalOrd> Iterator<&'self K> for FlatSetSymDifference<'self, K> {
+    fn next(&mut self) -> Option<&'self K> {
+        loop {
+            if self.a.is_empty() && self.b.is_empty() {
+                return None;
+            }
+            match cmp_heads(self.a, self.b, Greater, Less) {
+                Less => return Some(shift_slice(&mut self.a)),
+                Equal => {
+                    shift_slice(&mut self.a);
+                    shift_slice(&mut self.b);
+                }
+                Greater => return Some(shift_slice(&mut self.b)),
+            }
+        }
+    }
+}
+
+impl<'self, K: TotalOrd> Iterator<&'self K> for FlatSetIntersection<'self, K> {
+    fn next(&mut self) -> Option<&'self K> {
+        loop {
+            if self.a.is_empty() || self.b.is_empty() {
+                return None;
+            }
+            match cmp_heads(self.a, self.b, Less, Less) {
+                Less => { shift_slice(&mut self.a); }
+                Equal => {
+                    shift_slice(&mut self.b);
+                    return Some(shift_slice(&mut self.a));
+                }
+                Greater => { shift_slice(&mut self.b); }
+            }
+        }
+    }
+}
+
+impl<'self, K: TotalOrd> Iterator<&'self K> for FlatSetUnion<'self, K> {
+    fn next(&mut self) -> Option<&'self K> {
+        if self.a.is_empty() && self.b.is_empty() {
+            return None;
+        }
+        match cmp_heads(self.a, self.b, Greater, Less) {
+            Less => Some(shift_slice(&mut self.a)),
+            Equal => {
+                shift_slice(&mut self.b);
+                Some(shift_slice(&mut self.a))
+            }
+            Greater => Some(shift_slice(&mut self.b)),
+        }
+    }
+}
+
+/// A flat map implementation which stores keys and values in two parallel
+/// sorted vectors ~[K] and ~[V], so searching the keys does not pull the
+/// values into the cache. It has the API of FlatMap, with its own entry,
+/// cursor and iterator types, except for `diff`, `prefix_iter`,
+/// `longest_prefix_match` and `write_to`, which work on the pairs in place.
+/// Like FlatMap it keeps its keys in their natural order.
+#[deriving(Clone, Eq)]
+pub struct SoaFlatMap<K, V> {
+    priv keys: ~[K],
+    priv values: ~[V],
+}
+
+/// Split sorted pairs into parallel key and value vectors.
+fn unzip_pairs<K, V>(data: ~[(K, V)]) -> (~[K], ~[V]) {
+    let mut keys = vec::with_capacity(data.len());
+    let mut values = vec::with_capacity(data.len());
+    for (k, v) in data.move_iter() {
+        keys.push(k);
+        values.push(v);
+    }
+    (keys, values)
+}
+
+impl<K: TotalOrd, V> SoaFlatMap<K, V> {
+    /// Creates an empty SoaFlatMap.
+    pub fn new() -> SoaFlatMap<K, V> {
+        SoaFlatMap::with_capacity(0)
+    }
+
+    /// Create an empty SoaFlatMap with space for at least `n` elements.
+    pub fn with_capacity(capacity: uint) -> SoaFlatMap<K, V> {
+        SoaFlatMap{keys: vec::with_capacity(capacity),
+                   values: vec::with_capacity(capacity)}
+    }
+
+    /// Create a SoaFlatMap from pairs in any order in O(n log n), resolving
+    /// equal keys according to `dups`.
+    pub fn from_vec(data: ~[(K, V)], dups: Duplicates<K, V>) -> SoaFlatMap<K, V> {
+        let data = FlatMap::from_vec(data, dups).into_data();
+        let (keys, values) = unzip_pairs(data);
+        SoaFlatMap{keys: keys, values: values}
+    }
+
+    /// Create a SoaFlatMap from pairs which are already sorted by strictly
+    /// ascending key. Fails if `data` is not sorted or contains equal keys.
+    pub fn from_sorted_vec(data: ~[(K, V)]) -> SoaFlatMap<K, V> {
+        let data = FlatMap::from_sorted_vec(data).into_data();
+        let (keys, values) = unzip_pairs(data);
+        SoaFlatMap{keys: keys, values: values}
+    }
+
+    /// Create a SoaFlatMap from an iterator of pairs in O(n log n),
+    /// resolving equal keys according to `dups`.
+    pub fn from_iter_with<T: Iterator<(K, V)>>(iter: &mut T, dups: Duplicates<K, V>)
+                                              -> SoaFlatMap<K, V> {
+        let data: ~[(K, V)] = iter.collect();
+        SoaFlatMap::from_vec(data, dups)
+    }
+
+    /// Add the pairs of an iterator to the map in O((n + m) log m), where
+    /// `m` is the number of new pairs. Keys already in the map count as
+    /// seen first when resolving equal keys according to `dups`.
+    pub fn extend_with<T: Iterator<(K, V)>>(&mut self, iter: &mut T,
+                                            dups: Duplicates<K, V>) {
+        let mut map = self.take_flatmap();
+        map.extend_with(iter, dups);
+        self.put_flatmap(map);
+    }
+
+    /// Combine two maps in O(n + m), calling `f` with the key and both
+    /// values, this map's first, for each key the maps have in common.
+    pub fn merge(self, other: SoaFlatMap<K, V>, f: &fn(&K, V, V) -> V) -> SoaFlatMap<K, V> {
+        let mut map = self;
+        let mut pairs = map.take_flatmap();
+        pairs.map.merge_data(other.into_data(), MergeWith(f));
+        map.put_flatmap(pairs);
+        map
+    }
+
+    /// Move all pairs of `other` into this map in O(n + m). Values from
+    /// `other` replace those of equal keys, as inserting each pair would.
+    pub fn append(&mut self, other: SoaFlatMap<K, V>) {
+        let mut map = self.take_flatmap();
+        map.map.merge_data(other.into_data(), KeepLast);
+        self.put_flatmap(map);
+    }
+
+    /// Collapse each run of keys for which `same` returns true when called
+    /// with the first key of the run into that first key, resolving the
+    /// values according to `dups`.
+    pub fn dedup_by(&mut self, same: &fn(&K, &K) -> bool, dups: Duplicates<K, V>) {
+        let mut map = self.take_flatmap();
+        map.dedup_by(same, dups);
+        self.put_flatmap(map);
+    }
+
+    // The bulk edits which reorder or combine pairs go through a FlatMap.
+    // Zipping and unzipping the vectors is O(n), like the edits themselves.
+    fn take_flatmap(&mut self) -> FlatMap<K, V> {
+        let keys = replace(&mut self.keys, ~[]);
+        let values = replace(&mut self.values, ~[]);
+        FlatMap::from_data(keys.move_iter().zip(values.move_iter()).collect())
+    }
+
+    fn put_flatmap(&mut self, map: FlatMap<K, V>) {
+        let (keys, values) = unzip_pairs(map.into_data());
+        self.keys = keys;
+        self.values = values;
+    }
+
+    fn into_data(self) -> ~[(K, V)] {
+        let SoaFlatMap{keys, values} = self;
+        keys.move_iter().zip(values.move_iter()).collect()
+    }
+
+    /// Return the capacity of the underlying vectors.
+    pub fn capacity(&self) -> uint {
+        self.keys.capacity()
+    }
+
+    /// Keep only the pairs for which `f` returns true, in one pass.
+    pub fn retain(&mut self, f: &fn(&K, &V) -> bool) {
+        let mut kept = 0;
+        for i in range(0, self.keys.len()) {
+            if f(&self.keys[i], &self.values[i]) {
+                self.keys.swap(kept, i);
+                self.values.swap(kept, i);
+                kept += 1;
+            }
+        }
+        self.keys.truncate(kept);
+        self.values.truncate(kept);
+    }
+
+    /// Remove the pairs whose key lies between `lo` and `hi` in O(n),
+    /// returning them in order.
+    pub fn drain_range(&mut self, lo: Bound<K>, hi: Bound<K>) -> ~[(K, V)] {
+        let (start, end) = keys_range_indices(self.keys, lo, hi);
+        let (tail_keys, tail_values) = self.split_tail(end);
+        let (keys, values) = self.split_tail(start);
+        self.keys.push_all_move(tail_keys);
+        self.values.push_all_move(tail_values);
+        keys.move_iter().zip(values.move_iter()).collect()
+    }
+
+    /// Split the map in two at `key`, leaving the pairs whose key is less
+    /// than `key` in this map and returning the rest.
+    pub fn split_off(&mut self, key: &K) -> SoaFlatMap<K, V> {
+        let (keys, values) = self.split_tail(keys_lower_bound_index(self.keys, key));
+        SoaFlatMap{keys: keys, values: values}
+    }
+
+    /// Remove pairs from the front of the map, keeping the last `len`.
+    pub fn truncate_front(&mut self, len: uint) {
+        if len < self.keys.len() {
+            let at = self.keys.len() - len;
+            let (keys, values) = self.split_tail(at);
+            self.keys = keys;
+            self.values = values;
+        }
+    }
+
+    /// Remove pairs from the back of the map, keeping the first `len`.
+    pub fn truncate_back(&mut self, len: uint) {
+        self.keys.truncate(len);
+        self.values.truncate(len);
+    }
+
+    /// Move the keys and values from `at` onwards out of the map, in order.
+    fn split_tail(&mut self, at: uint) -> (~[K], ~[V]) {
+        let mut keys = vec::with_capacity(self.keys.len() - at);
+        let mut values = vec::with_capacity(self.keys.len() - at);
+        while self.keys.len() > at {
+            keys.push(self.keys.pop());
+            values.push(self.values.pop());
+        }
+        keys.reverse();
+        values.reverse();
+        (keys, values)
+    }
+
+    /// Make room for at least `additional` more pairs, returning an error
+    /// instead of failing if memory runs out.
+    pub fn try_reserve(&mut self, additional: uint) -> Result<(), AllocError> {
+        match try_reserve_vec(&mut self.keys, additional) {
+            Ok(()) => try_reserve_vec(&mut self.values, additional),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Insert a key-value pair like `insert`, returning an error instead of
+    /// failing if memory runs out. Replacing the value of a key which is
+    /// already present never allocates.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<bool, AllocError> {
+        let index = keys_lower_bound_index(self.keys, &key);
+        if index < self.keys.len() && self.keys[index].cmp(&key) == Equal {
+            self.values[index] = value;
+            return Ok(false);
+        }
+        match self.try_reserve(1) {
+            Ok(()) => {
+                self.keys.insert(index, key);
+                self.values.insert(index, value);
+                Ok(true)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Release the spare capacity of the underlying vectors.
+    pub fn shrink_to_fit(&mut self) {
+        self.keys.shrink_to_fit();
+        self.values.shrink_to_fit();
+    }
+
+    /// Report how much memory the map holds for its keys and values.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let (ks, vs) = (sys::size_of::<K>(), sys::size_of::<V>());
+        MemoryUsage{used: self.keys.len() * (ks + vs),
+                    allocated: self.keys.capacity() * ks + self.values.capacity() * vs}
+    }
+
+    /// Return the number of keys in the map which are less than `key`.
+    pub fn rank(&self, key: &K) -> uint {
+        keys_lower_bound_index(self.keys, key)
+    }
+
+    /// Return the pair at position `n` in order, or None if the map has
+    /// `n` or fewer pairs.
+    pub fn select<'a>(&'a self, n: uint) -> Option<(&'a K, &'a V)> {
+        if n < self.keys.len() {
+            Some((&self.keys[n], &self.values[n]))
+        } else {
+            None
+        }
+    }
+
+    /// Return the pair at position `n` in order, with a mutable reference
+    /// to the value, or None if the map has `n` or fewer pairs.
+    pub fn nth_mut<'a>(&'a mut self, n: uint) -> Option<(&'a K, &'a mut V)> {
+        if n < self.keys.len() {
+            Some((&self.keys[n], &mut self.values[n]))
+        } else {
+            None
+        }
+    }
+
+    /// Return the pair at the `p`th percentile of the keys, by the nearest
+    /// rank method, or None if the map is empty. Fails if `p` is not
+    /// between 0 and 100.
+    pub fn percentile<'a>(&'a self, p: f64) -> Option<(&'a K, &'a V)> {
+        self.select(nearest_rank(p, self.keys.len()))
+    }
+
+    /// Return the key at the `p`th percentile when each key is counted
+    /// `weight(value)` times, as in a histogram of counts, or None if the
+    /// total weight is zero. Fails if `p` is not between 0 and 100.
+    pub fn weighted_percentile<'a>(&'a self, p: f64, weight: &fn(&V) -> uint)
+                                   -> Option<&'a K> {
+        let total = self.values.iter().fold(0u, |sum, v| sum + weight(v));
+        let target = nearest_rank(p, total);
+        let mut seen = 0u;
+        for (k, v) in self.iter() {
+            seen += weight(v);
+            if seen > target {
+                return Some(k);
+            }
+        }
+        None
+    }
+
+    #[inline]
+    fn find_index(&self, key: &K) -> Option<uint> {
+        self.keys.bsearch(|k| k.cmp(key))
+    }
+
+    /// Get the entry for a key in the map for in-place manipulation. The key
+    /// is searched for once and the entry remembers where it was found.
+    pub fn entry<'a>(&'a mut self, key: K) -> SoaEntry<'a, K, V> {
+        let index = keys_lower_bound_index(self.keys, &key);
+        if index < self.keys.len() && self.keys[index].cmp(&key) == Equal {
+            SoaOccupied(SoaOccupiedEntry{map: self, index: index})
+        } else {
+            SoaVacant(SoaVacantEntry{map: self, key: key, index: index})
+        }
+    }
+
+    /// Modify and return the value corresponding to the key in the map, or
+    /// insert and return a new value if it doesn't exist.
+    pub fn mangle<'a,A>(&'a mut self, k: K, a: A, not_found: &fn(&K, A) -> V,
+                        found: &fn(&K, &mut V, A)) -> &'a mut V {
+        let index = keys_lower_bound_index(self.keys, &k);
+        if index < self.keys.len() && self.keys[index].cmp(&k) == Equal {
+            found(&self.keys[index], &mut self.values[index], a);
+        } else {
+            let v = not_found(&k, a);
+            self.keys.insert(index, k);
+            self.values.insert(index, v);
+        }
+        &mut self.values[index]
+    }
+
+    /// Return the value corresponding to the key in the map, or insert
+    /// and return the value if it doesn't exist.
+    pub fn find_or_insert<'a>(&'a mut self, k: K, v: V) -> &'a mut V {
+        self.mangle(k, v, |_k, a| a, |_k,_v,_a| ())
+    }
+
+    /// Return the value corresponding to the key in the map, or create,
+    /// insert, and return a new value if it doesn't exist.
+    pub fn find_or_insert_with<'a>(&'a mut self, k: K, f: &fn(&K) -> V)
+                               -> &'a mut V {
+        self.mangle(k, (), |k,_a| f(k), |_k,_v,_a| ())
+    }
+
+    /// Insert a key-value pair into the map if the key is not already present.
+    /// Otherwise, modify the existing value for the key.
+    /// Returns the new or modified value for the key.
+    pub fn insert_or_update_with<'a>(&'a mut self, k: K, v: V,
+                                     f: &fn(&K, &mut V)) -> &'a mut V {
+        self.mangle(k, v, |_k,a| a, |k,v,_a| f(k,v))
+    }
+
+    /// Return the value corresponding to the key in the map, using
+    /// equivalence
+    pub fn find_equiv<'a, Q: OrdEquiv<K>>(&'a self, key: &Q)
+                                             -> Option<&'a V> {
+        let index = lower_bound_by(self.keys, |k| key.equiv_cmp(k) == Greater);
+        if index < self.keys.len() && key.equiv(&self.keys[index]) {
+            Some(&self.values[index])
+        } else {
+            None
+        }
+    }
+
+    /// An iterator visiting all key-value pairs in order.
+    /// Iterator element type is (&'a K, &'a V).
+    pub fn iter<'a>(&'a self) -> SoaFlatMapIterator<'a, K, V> {
+        self.slice_iter(0, self.keys.len())
+    }
+
+    /// An iterator visiting all key-value pairs in order,
+    /// with mutable references to the values.
+    /// Iterator element type is (&'a K, &'a mut V).
+    pub fn mut_iter<'a>(&'a mut self) -> SoaFlatMapMutIterator<'a, K, V> {
+        SoaFlatMapMutIterator{keys: self.keys.iter(), values: self.values.mut_iter()}
+    }
+
+    /// Creates a consuming iterator, that is, one that moves each key-value
+    /// pair out of the map in order. The map cannot be used after calling
+    /// this.
+    pub fn move_iter(self) -> SoaFlatMapMoveIterator<K, V> {
+        let SoaFlatMap{keys, values} = self;
+        SoaFlatMapMoveIterator{keys: keys.move_iter(), values: values.move_iter()}
+    }
+
+    /// An iterator visiting all keys in order.
+    pub fn keys<'a>(&'a self) -> vec::VecIterator<'a, K> {
+        self.keys.iter()
+    }
+
+    /// An iterator visiting all values in key order.
+    pub fn values<'a>(&'a self) -> vec::VecIterator<'a, V> {
+        self.values.iter()
+    }
+
+    /// An iterator visiting the key-value pairs whose key is not less than
+    /// `key`, in order.
+    pub fn lower_bound<'a>(&'a self, key: &K) -> SoaFlatMapIterator<'a, K, V> {
+        self.slice_iter(keys_lower_bound_index(self.keys, key), self.keys.len())
+    }
+
+    /// An iterator visiting the key-value pairs whose key is greater than
+    /// `key`, in order.
+    pub fn upper_bound<'a>(&'a self, key: &K) -> SoaFlatMapIterator<'a, K, V> {
+        self.slice_iter(keys_upper_bound_index(self.keys, key), self.keys.len())
+    }
+
+    /// An iterator visiting the key-value pairs whose key lies between
+    /// `lo` and `hi`, in order.
+    pub fn range<'a>(&'a self, lo: Bound<K>, hi: Bound<K>)
+                     -> SoaFlatMapIterator<'a, K, V> {
+        let (start, end) = keys_range_indices(self.keys, lo, hi);
+        self.slice_iter(start, end)
+    }
+
+    fn slice_iter<'a>(&'a self, start: uint, end: uint) -> SoaFlatMapIterator<'a, K, V> {
+        SoaFlatMapIterator{keys: self.keys.slice(start, end).iter(),
+                           values: self.values.slice(start, end).iter()}
+    }
+
+    /// An iterator visiting all key-value pairs in reverse order.
+    pub fn rev_iter<'a>(&'a self) -> Invert<SoaFlatMapIterator<'a, K, V>> {
+        self.iter().invert()
+    }
+
+    /// An iterator visiting all key-value pairs in reverse order,
+    /// with mutable references to the values.
+    pub fn mut_rev_iter<'a>(&'a mut self) -> Invert<SoaFlatMapMutIterator<'a, K, V>> {
+        self.mut_iter().invert()
+    }
+
+    /// Creates a consuming iterator which moves each key-value pair out
+    /// of the map in reverse order.
+    pub fn move_rev_iter(self) -> Invert<SoaFlatMapMoveIterator<K, V>> {
+        self.move_iter().invert()
+    }
+
+    /// A cursor on the first pair of the map.
+    pub fn cursor<'a>(&'a self) -> SoaCursor<'a, K, V> {
+        SoaCursor{keys: self.keys.as_slice(), values: self.values.as_slice(), index: 0}
+    }
+
+    /// A cursor on the first pair of the map which can modify the map.
+    pub fn cursor_mut<'a>(&'a mut self) -> SoaCursorMut<'a, K, V> {
+        SoaCursorMut{map: self, index: 0}
+    }
+
+    /// Convert into a read-only map with a faster search, for maps which
+    /// are queried much more often than they are modified.
+    pub fn freeze(self) -> FrozenFlatMap<K, V, NaturalOrder> {
+        FrozenFlatMap::from_sorted(self.into_data(), NaturalOrder)
+    }
+}
+
+impl<K: TotalOrd + fmt::Default, V> SoaFlatMap<K, V> {
+    /// Retrieves a value for the given key, failing if the key is not
+    /// present.
+    pub fn get<'a>(&'a self, k: &K) -> &'a V {
+        match self.find(k) {
+            Some(v) => v,
+            None => fail2!("No entry found for key: {}", k),
+        }
+    }
+
+    /// Retrieves a (mutable) value for the given key, failing if the key
+    /// is not present.
+    pub fn get_mut<'a>(&'a mut self, k: &K) -> &'a mut V {
+        match self.find_mut(k) {
+            Some(v) => v,
+            None => fail2!("No entry found for key: {}", k),
+        }
+    }
+}
+
+impl<K, V> Container for SoaFlatMap<K, V> {
+    /// Return the number of elements in the map.
+    fn len(&self) -> uint {
+        self.keys.len()
+    }
+}
+
+impl<K, V> Mutable for SoaFlatMap<K, V> {
+    /// Clear the map, removing all items.
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.values.clear();
+    }
+}
+
+impl<K: TotalOrd, V> FromIterator<(K, V)> for SoaFlatMap<K, V> {
+    fn from_iterator<T: Iterator<(K, V)>>(iter: &mut T) -> SoaFlatMap<K, V> {
+        SoaFlatMap::from_iter_with(iter, KeepLast)
+    }
+}
+
+impl<K: TotalOrd, V> Extendable<(K, V)> for SoaFlatMap<K, V> {
+    fn extend<T: Iterator<(K, V)>>(&mut self, iter: &mut T) {
+        self.extend_with(iter, KeepLast);
+    }
+}
+
+impl<K: TotalOrd, V> Default for SoaFlatMap<K, V> {
+    fn default() -> SoaFlatMap<K, V> { SoaFlatMap::new() }
+}
+
+// Lexicographical comparison
+fn soa_lt<K: TotalOrd, V: Ord>(a: &SoaFlatMap<K, V>,
+                               b: &SoaFlatMap<K, V>) -> bool {
+    // the Zip iterator is as long as the shortest of a and b.
+    for ((key_a, value_a), (key_b, value_b)) in a.iter().zip(b.iter()) {
+        match key_a.cmp(key_b) {
+            Less => return true,
+            Greater => return false,
+            Equal => {}
+        }
+        if *value_a < *value_b { return true; }
+        if *value_a > *value_b { return false; }
+    }
+
+    a.len() < b.len()
+}
+
+impl<K: TotalOrd, V: Ord> Ord for SoaFlatMap<K, V> {
+    #[inline]
+    fn lt(&self, other: &SoaFlatMap<K, V>) -> bool { soa_lt(self, other) }
+    #[inline]
+    fn le(&self, other: &SoaFlatMap<K, V>) -> bool { !soa_lt(other, self) }
+    #[inline]
+    fn ge(&self, other: &SoaFlatMap<K, V>) -> bool { !soa_lt(self, other) }
+    #[inline]
+    fn gt(&self, other: &SoaFlatMap<K, V>) -> bool { soa_lt(other, self) }
+}
+
+impl<K: TotalOrd, V: TotalEq> TotalEq for SoaFlatMap<K, V> {
+    fn equals(&self, other: &SoaFlatMap<K, V>) -> bool {
+        self.len() == other.len() &&
+            self.iter().zip(other.iter()).all(|((key_a, value_a), (key_b, value_b))| {
+                key_a.cmp(key_b) == Equal && value_a.equals(value_b)
+            })
+    }
+}
+
+// Lexicographical comparison
+impl<K: TotalOrd, V: TotalOrd> TotalOrd for SoaFlatMap<K, V> {
+    fn cmp(&self, other: &SoaFlatMap<K, V>) -> Ordering {
+        for ((key_a, value_a), (key_b, value_b)) in self.iter().zip(other.iter()) {
+            match key_a.cmp(key_b) {
+                Equal => {}
+                order => return order,
+            }
+            match value_a.cmp(value_b) {
+                Equal => {}
+                order => return order,
+            }
+        }
+        self.len().cmp(&other.len())
+    }
+}
+
+// Hashes like a FlatMap with the same pairs
+impl<K: IterBytes, V: IterBytes> IterBytes for SoaFlatMap<K, V> {
+    fn iter_bytes(&self, lsb0: bool, f: to_bytes::Cb) -> bool {
+        self.len().iter_bytes(lsb0, |b| f(b)) &&
+            self.keys.iter().zip(self.values.iter()).all(|(k, v)| {
+                k.iter_bytes(lsb0, |b| f(b)) && v.iter_bytes(lsb0, |b| f(b))
+            })
+    }
+}
+
+// Formats as `{k: v, ...}`
+impl<K: fmt::Default, V: fmt::Default> fmt::Default for SoaFlatMap<K, V> {
+    fn fmt(map: &SoaFlatMap<K, V>, f: &mut fmt::Formatter) {
+        write!(f.buf, "\\{");
+        for (i, (k, v)) in map.keys.iter().zip(map.values.iter()).enumerate() {
+            if i > 0 {
+                write!(f.buf, ", ");
+            }
+            write!(f.buf, "{}: {}", *k, *v);
+        }
+        write!(f.buf, "\\}");
+    }
+}
+
+impl<K: ToStr, V: ToStr> ToStr for SoaFlatMap<K, V> {
+    fn to_str(&self) -> ~str {
+        let pairs = self.keys.iter().zip(self.values.iter())
+                        .map(|(k, v)| format!("{}: {}", k.to_str(), v.to_str()))
+                        .collect::<~[~str]>();
+        format!("\\{{}\\}", pairs.connect(", "))
+    }
+}
+
+// Fails if the key is not present, like `get`. `Index` hands the value back
+// by value, so every `map[key]` clones it; borrow it with `get` or `find`
+// instead when that is costly.
+impl<K: TotalOrd + fmt::Default, V: Clone> Index<K, V> for SoaFlatMap<K, V> {
+    fn index(&self, key: &K) -> V {
+        self.get(key).clone()
+    }
+}
+
+impl<K: TotalOrd, V> Map<K, V> for SoaFlatMap<K, V> {
+    fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        match self.find_index(key) {
+            None => None,
+            Some(idx) => Some(&self.values[idx]),
+        }
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.find_index(key).is_some()
+    }
+}
+
+impl<K: TotalOrd, V> MutableMap<K, V> for SoaFlatMap<K, V> {
+    fn find_mut<'a>(&'a mut self, key: &K) -> Option<&'a mut V> {
+        match self.find_index(key) {
+            None => None,
+            Some(idx) => Some(&mut self.values[idx]),
+        }
+    }
+
+    fn swap(&mut self, k: K, v: V) -> Option<V> {
+        let index = keys_lower_bound_index(self.keys, &k);
+        if index < self.keys.len() && self.keys[index].cmp(&k) == Equal {
+            Some(replace(&mut self.values[index], v))
+        } else {
+            self.keys.insert(index, k);
+            self.values.insert(index, v);
+            None
+        }
+    }
+
+    fn pop(&mut self, key: &K) -> Option<V> {
+        match self.find_index(key) {
+            None => None,
+            Some(idx) => {
+                self.keys.remove(idx);
+                Some(self.values.remove(idx))
+            }
+        }
+    }
+}
+
+/// A view into a single key of a SoaFlatMap, which is either occupied or
+/// vacant.
+pub enum SoaEntry<'self, K, V> {
+    /// The key is in the map.
+    SoaOccupied(SoaOccupiedEntry<'self, K, V>),
+    /// The key is not in the map.
+    SoaVacant(SoaVacantEntry<'self, K, V>),
+}
+
+/// An entry for a key that is in a SoaFlatMap, holding the index of its key
+/// and value.
+pub struct SoaOccupiedEntry<'self, K, V> {
+    priv map: &'self mut SoaFlatMap<K, V>,
+    priv index: uint,
+}
+
+/// An entry for a key that is not in a SoaFlatMap, holding the index its
+/// key and value would be inserted at.
+pub struct SoaVacantEntry<'self, K, V> {
+    priv map: &'self mut SoaFlatMap<K, V>,
+    priv key: K,
+    priv index: uint,
+}
+
+impl<'self, K, V> SoaEntry<'self, K, V> {
+    /// Return the value of the entry, inserting `default` if it is vacant.
+    pub fn or_insert(self, default: V) -> &'self mut V {
+        match self {
+            SoaOccupied(e) => e.into_mut(),
+            SoaVacant(e) => e.insert(default),
+        }
+    }
+
+    /// Return the value of the entry, inserting the result of `f` if it is
+    /// vacant.
+    pub fn or_insert_with(self, f: &fn(&K) -> V) -> &'self mut V {
+        match self {
+            SoaOccupied(e) => e.into_mut(),
+            SoaVacant(e) => {
+                let v = f(e.key());
+                e.insert(v)
+            }
+        }
+    }
+
+    /// Modify the value of an occupied entry in place. A vacant entry is
+    /// returned unchanged.
+    pub fn and_modify(self, f: &fn(&K, &mut V)) -> SoaEntry<'self, K, V> {
+        match self {
+            SoaOccupied(e) => {
+                let mut e = e;
+                {
+                    let (k, v) = e.pair_mut();
+                    f(k, v);
+                }
+                SoaOccupied(e)
+            }
+            SoaVacant(e) => SoaVacant(e),
+        }
+    }
+}
+
+impl<'self, K, V> SoaOccupiedEntry<'self, K, V> {
+    /// Return the key of the entry.
+    pub fn key<'a>(&'a self) -> &'a K {
+        &self.map.keys[self.index]
+    }
+
+    /// Return the value of the entry.
+    pub fn get<'a>(&'a self) -> &'a V {
+        &self.map.values[self.index]
+    }
+
+    /// Return the (mutable) value of the entry.
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut V {
+        &mut self.map.values[self.index]
+    }
+
+    /// Convert the entry into a reference to its value with the lifetime of
+    /// the map borrow.
+    pub fn into_mut(self) -> &'self mut V {
+        let SoaOccupiedEntry{map, index} = self;
+        &mut map.values[index]
+    }
+
+    /// Replace the value of the entry, returning the old value.
+    pub fn insert(&mut self, value: V) -> V {
+        replace(self.get_mut(), value)
+    }
+
+    /// Remove the entry from the map, returning its value.
+    pub fn remove(self) -> V {
+        let SoaOccupiedEntry{map, index} = self;
+        map.keys.remove(index);
+        map.values.remove(index)
+    }
+
+    fn pair_mut<'a>(&'a mut self) -> (&'a K, &'a mut V) {
+        (&self.map.keys[self.index], &mut self.map.values[self.index])
+    }
+}
+
+impl<'self, K, V> SoaVacantEntry<'self, K, V> {
+    /// Return the key the entry was created with.
+    pub fn key<'a>(&'a self) -> &'a K {
+        &self.key
+    }
+
+    /// Insert a value for the key at the position found by the search,
+    /// returning a reference to it.
+    pub fn insert(self, value: V) -> &'self mut V {
+        let SoaVacantEntry{map, key, index} = self;
+        map.keys.insert(index, key);
+        map.values.insert(index, value);
+        &mut map.values[index]
+    }
+}
+
+/// SoaFlatMap iterator
+pub struct SoaFlatMapIterator<'self, K, V> {
+    priv keys: vec::VecIterator<'self, K>,
+    priv values: vec::VecIterator<'self, V>,
+}
+
+impl<'self, K, V> Iterator<(&'self K, &'self V)> for SoaFlatMapIterator<'self, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<(&'self K, &'self V)> {
+        match (self.keys.next(), self.values.next()) {
+            (Some(k), Some(v)) => Some((k, v)),
+            _ => None
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        return self.keys.size_hint();
+    }
+}
+
+impl<'self, K, V> DoubleEndedIterator<(&'self K, &'self V)> for SoaFlatMapIterator<'self, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<(&'self K, &'self V)> {
+        match (self.keys.next_back(), self.values.next_back()) {
+            (Some(k), Some(v)) => Some((k, v)),
+            _ => None
+        }
+    }
+}
+
+/// SoaFlatMap mutable values iterator
+pub struct SoaFlatMapMutIterator<'self, K, V> {
+    priv keys: vec::VecIterator<'self, K>,
+    priv values: vec::VecMutIterator<'self, V>,
+}
+
+impl<'self, K, V> Iterator<(&'self K, &'self mut V)> for SoaFlatMapMutIterator<'self, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<(&'self K, &'self mut V)> {
+        match (self.keys.next(), self.values.next()) {
+            (Some(k), Some(v)) => Some((k, v)),
+            _ => None
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        return self.keys.size_hint();
+    }
+}
+
+impl<'self, K, V> DoubleEndedIterator<(&'self K, &'self mut V)>
+        for SoaFlatMapMutIterator<'self, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<(&'self K, &'self mut V)> {
+        match (self.keys.next_back(), self.values.next_back()) {
+            (Some(k), Some(v)) => Some((k, v)),
+            _ => None
+        }
+    }
+}
+
+/// SoaFlatMap move iterator
+pub struct SoaFlatMapMoveIterator<K, V> {
+    priv keys: vec::MoveIterator<K>,
+    priv values: vec::MoveIterator<V>,
+}
+
+impl<K, V> Iterator<(K, V)> for SoaFlatMapMoveIterator<K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<(K, V)> {
+        match (self.keys.next(), self.values.next()) {
+            (Some(k), Some(v)) => Some((k, v)),
+            _ => None
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        return self.keys.size_hint();
+    }
+}
+
+impl<K, V> DoubleEndedIterator<(K, V)> for SoaFlatMapMoveIterator<K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<(K, V)> {
+        match (self.keys.next_back(), self.values.next_back()) {
+            (Some(k), Some(v)) => Some((k, v)),
+            _ => None
+        }
+    }
+}
+
+/// A read-only cursor over a SoaFlatMap. The cursor is either on a pair or
+/// just past the last pair.
+pub struct SoaCursor<'self, K, V> {
+    priv keys: &'self [K],
+    priv values: &'self [V],
+    priv index: uint,
+}
+
+impl<'self, K: TotalOrd, V> SoaCursor<'self, K, V> {
+    /// Return the pair the cursor is on, or None if it is past the end.
+    pub fn get(&self) -> Option<(&'self K, &'self V)> {
+        if self.index < self.keys.len() {
+            Some((&self.keys[self.index], &self.values[self.index]))
+        } else {
+            None
+        }
+    }
+
+    /// Return the position of the cursor, which is the number of pairs
+    /// before it.
+    pub fn index(&self) -> uint {
+        self.index
+    }
+
+    /// Move to the next pair, returning false if the cursor was already
+    /// past the end.
+    pub fn move_next(&mut self) -> bool {
+        if self.index < self.keys.len() {
+            self.index += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Move to the previous pair, returning false if the cursor was
+    /// already on the first pair.
+    pub fn move_prev(&mut self) -> bool {
+        if self.index > 0 {
+            self.index -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Move to the first pair whose key is not less than `key`, returning
+    /// true if its key is equal to `key`.
+    pub fn seek(&mut self, key: &K) -> bool {
+        self.index = seek_by(self.keys, self.index, |k| k.cmp(key) == Less);
+        self.index < self.keys.len() && self.keys[self.index].cmp(key) == Equal
+    }
+}
+
+/// A cursor over a SoaFlatMap which can modify values and insert or remove
+/// pairs at its position. The cursor is either on a pair or just past the
+/// last pair.
+pub struct SoaCursorMut<'self, K, V> {
+    priv map: &'self mut SoaFlatMap<K, V>,
+    priv index: uint,
+}
+
+impl<'self, K: TotalOrd, V> SoaCursorMut<'self, K, V> {
+    /// Return the pair the cursor is on, or None if it is past the end.
+    pub fn get<'a>(&'a self) -> Option<(&'a K, &'a V)> {
+        self.map.select(self.index)
+    }
+
+    /// Return the pair the cursor is on with a mutable reference to the
+    /// value, or None if it is past the end.
+    pub fn get_mut<'a>(&'a mut self) -> Option<(&'a K, &'a mut V)> {
+        self.map.nth_mut(self.index)
+    }
+
+    /// Return the position of the cursor, which is the number of pairs
+    /// before it.
+    pub fn index(&self) -> uint {
+        self.index
+    }
+
+    /// Move to the next pair, returning false if the cursor was already
+    /// past the end.
+    pub fn move_next(&mut self) -> bool {
+        if self.index < self.map.keys.len() {
+            self.index += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Move to the previous pair, returning false if the cursor was
+    /// already on the first pair.
+    pub fn move_prev(&mut self) -> bool {
+        if self.index > 0 {
+            self.index -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Move to the first pair whose key is not less than `key`, returning
+    /// true if its key is equal to `key`.
+    pub fn seek(&mut self, key: &K) -> bool {
+        let keys = self.map.keys.as_slice();
+        self.index = seek_by(keys, self.index, |k| k.cmp(key) == Less);
+        self.index < keys.len() && keys[self.index].cmp(key) == Equal
+    }
+
+    /// Insert a pair at the position of the cursor, leaving the cursor on
+    /// it. Fails if the key does not sort strictly between the keys of the
+    /// pairs before and after the cursor.
+    pub fn insert(&mut self, key: K, value: V) {
+        let index = self.index;
+        {
+            let keys = self.map.keys.as_slice();
+            if (index > 0 && keys[index - 1].cmp(&key) != Less) ||
+               (index < keys.len() && key.cmp(&keys[index]) != Less) {
+                fail2!("SoaCursorMut::insert: key is out of order at index {}", index);
+            }
+        }
+        self.map.keys.insert(index, key);
+        self.map.values.insert(index, value);
+    }
+
+    /// Remove and return the pair the cursor is on, leaving the cursor on
+    /// the next pair, or None if it is past the end.
+    pub fn remove(&mut self) -> Option<(K, V)> {
+        if self.index < self.map.keys.len() {
+            Some((self.map.keys.remove(self.index), self.map.values.remove(self.index)))
+        } else {
+            None
+        }
+    }
+}
+
+/// Distance, in tree nodes, of the prefetch issued at each step of the
+/// Eytzinger search. Sixteen nodes ahead is four levels down the tree.
+static PREFETCH_NODES: uint = 16;
+
+/// A read-only map for maps which are built once and queried many times.
+/// Keys are stored in Eytzinger (breadth first) order, node `k` of the
+/// implicit search tree at index `k - 1` with children `2k` and `2k + 1`,
+/// so a search walks a predictable path through memory and can prefetch
+/// the levels below it.
+pub struct FrozenFlatMap<K, V, C> {
+    priv keys: ~[K],
+    priv values: ~[V],
+    priv compare: C,
+}
+
+/// Fill `order` so that `order[k - 1]` is the sorted position of node `k`.
+fn eytzinger_order(k: uint, next: &mut uint, order: &mut [uint]) {
+    if k <= order.len() {
+        eytzinger_order(2 * k, next, order);
+        order[k - 1] = *next;
+        *next += 1;
+        eytzinger_order(2 * k + 1, next, order);
+    }
+}
+
+fn eytzinger_permutation(n: uint) -> ~[uint] {
+    let mut order = vec::from_elem(n, 0u);
+    let mut next = 0u;
+    eytzinger_order(1, &mut next, order);
+    order
+}
+
+#[inline]
+fn prefetch<T>(a: &[T], i: uint) {
+    unsafe {
+        intrinsics::prefetch_read_data(ptr::offset(vec::raw::to_ptr(a), i as int), 3);
+    }
+}
+
+impl<K, V, C: Compare<K>> FrozenFlatMap<K, V, C> {
+    fn from_sorted(data: ~[(K, V)], compare: C) -> FrozenFlatMap<K, V, C> {
+        let n = data.len();
+        let mut slots: ~[Option<(K, V)>] = data.move_iter().map(|p| Some(p)).collect();
+        let mut keys = vec::with_capacity(n);
+        let mut values = vec::with_capacity(n);
+        for &i in eytzinger_permutation(n).iter() {
+            let (k, v) = replace(&mut slots[i], None).unwrap();
+            keys.push(k);
+            values.push(v);
+        }
+        FrozenFlatMap{keys: keys, values: values, compare: compare}
+    }
+
+    /// Convert back into a FlatMap which can be modified.
+    pub fn thaw(self) -> FlatMapBy<K, V, C> {
+        let FrozenFlatMap{keys, values, compare} = self;
+        let n = keys.len();
+        let mut slots: ~[Option<(K, V)>] = vec::from_fn(n, |_| None);
+        let order = eytzinger_permutation(n);
+        for (node, pair) in keys.move_iter().zip(values.move_iter()).enumerate() {
+            slots[order[node]] = Some(pair);
+        }
+        FlatMapBy{data: slots.move_iter().map(|p| p.unwrap()).collect(), compare: compare}
+    }
+
+    /// Return the node of the first key which is not less than `key`, or 0
+    /// if all keys are less than `key`.
+    #[inline]
+    fn lower_bound_node(&self, key: &K) -> uint {
+        let n = self.keys.len();
+        let mut k = 1u;
+        while k <= n {
+            prefetch(self.keys, cmp::min(k * PREFETCH_NODES, n) - 1);
+            k = 2 * k + (self.compare.compare(&self.keys[k - 1], key) == Less) as uint;
+        }
+        // every right turn after the last left turn added a trailing one
+        while k & 1 == 1 {
+            k >>= 1;
+        }
+        k >> 1
+    }
+
+    #[inline]
+    fn find_node(&self, key: &K) -> Option<uint> {
+        let k = self.lower_bound_node(key);
+        if k != 0 && self.compare.compare(&self.keys[k - 1], key) == Equal {
+            Some(k)
+        } else {
+            None
+        }
+    }
+
+    /// An iterator visiting all key-value pairs in order.
+    /// Iterator element type is (&'a K, &'a V).
+    pub fn iter<'a>(&'a self) -> FrozenFlatMapIterator<'a, K, V, C> {
+        let n = self.keys.len();
+        let mut front = 1u;
+        while 2 * front <= n {
+            front = 2 * front;
+        }
+        let mut back = 1u;
+        while 2 * back + 1 <= n {
+            back = 2 * back + 1;
+        }
+        FrozenFlatMapIterator{map: self, front: front, back: back, remaining: n}
+    }
+}
+
+impl<K: fmt::Default, V, C: Compare<K>> FrozenFlatMap<K, V, C> {
+    /// Retrieves a value for the given key, failing if the key is not
+    /// present.
+    pub fn get<'a>(&'a self, k: &K) -> &'a V {
+        match self.find(k) {
+            Some(v) => v,
+            None => fail2!("No entry found for key: {}", k),
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, C: Compare<K>> Clone for FrozenFlatMap<K, V, C> {
+    fn clone(&self) -> FrozenFlatMap<K, V, C> {
+        FrozenFlatMap{keys: self.keys.clone(), values: self.values.clone(),
+                      compare: self.compare.clone()}
+    }
+}
+
+impl<K, V, C> Container for FrozenFlatMap<K, V, C> {
+    /// Return the number of elements in the map.
+    fn len(&self) -> uint {
+        self.keys.len()
+    }
+}
+
+impl<K, V, C: Compare<K>> Map<K, V> for FrozenFlatMap<K, V, C> {
+    fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        match self.find_node(key) {
+            None => None,
+            Some(k) => Some(&self.values[k - 1]),
+        }
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.find_node(key).is_some()
+    }
+}
+
+/// FrozenFlatMap iterator, walking the implicit tree in order.
+pub struct FrozenFlatMapIterator<'self, K, V, C> {
+    priv map: &'self FrozenFlatMap<K, V, C>,
+    priv front: uint,
+    priv back: uint,
+    priv remaining: uint,
+}
+
+impl<'self, K, V, C> FrozenFlatMapIterator<'self, K, V, C> {
+    #[inline]
+    fn pair(&self, k: uint) -> (&'self K, &'self V) {
+        (&self.map.keys[k - 1], &self.map.values[k - 1])
+    }
+}
+
+impl<'self, K, V, C> Iterator<(&'self K, &'self V)> for FrozenFlatMapIterator<'self, K, V, C> {
+    fn next(&mut self) -> Option<(&'self K, &'self V)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let n = self.map.keys.len();
+        let k = self.front;
+        // in order successor: the leftmost node of the right subtree, or
+        // the parent of the first ancestor which is a left child
+        let mut next = k;
+        if 2 * next + 1 <= n {
+            next = 2 * next + 1;
+            while 2 * next <= n {
+                next = 2 * next;
+            }
+        } else {
+            while next & 1 == 1 {
+                next >>= 1;
+            }
+            next >>= 1;
+        }
+        self.front = next;
+        Some(self.pair(k))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'self, K, V, C> DoubleEndedIterator<(&'self K, &'self V)> for FrozenFlatMapIterator<'self, K, V, C> {
+    fn next_back(&mut self) -> Option<(&'self K, &'self V)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let n = self.map.keys.len();
+        let k = self.back;
+        // in order predecessor: the rightmost node of the left subtree, or
+        // the parent of the first ancestor which is a right child
+        let mut prev = k;
+        if 2 * prev <= n {
+            prev = 2 * prev;
+            while 2 * prev + 1 <= n {
+                prev = 2 * prev + 1;
+            }
+        } else {
+            while prev != 0 && prev & 1 == 0 {
+                prev >>= 1;
+            }
+            prev >>= 1;
+        }
+        self.back = prev;
+        Some(self.pair(k))
+    }
+}
+
+/// Inline storage for a SmallFlatMap. Implemented for arrays of empty or
+/// full slots `[Option<T>, ..N]`, where `N` is the inline capacity.
+pub trait InlineArray<T> {
+    /// Return an array with every slot empty.
+    fn empty() -> Self;
+    /// Return the slots of the array.
+    fn slots<'a>(&'a self) -> &'a [Option<T>];
+    /// Return the (mutable) slots of the array.
+    fn mut_slots<'a>(&'a mut self) -> &'a mut [Option<T>];
+}
+
+macro_rules! inline_array_impl {
+    ($n:expr => $($none:expr),+) => {
+        impl<T> InlineArray<T> for [Option<T>, ..$n] {
+            #[inline]
+            fn empty() -> [Option<T>, ..$n] {
+                [$($none),+]
+            }
+
+            #[inline]
+            fn slots<'a>(&'a self) -> &'a [Option<T>] {
+                self.slice(0, $n)
+            }
+
+            #[inline]
+            fn mut_slots<'a>(&'a mut self) -> &'a mut [Option<T>] {
+                self.mut_slice(0, $n)
+            }
+        }
+    }
+}
+
+inline_array_impl!{1 => None}
+inline_array_impl!{2 => None, None}
+inline_array_impl!{4 => None, None, None, None}
+inline_array_impl!{8 => None, None, None, None, None, None, None, None}
+inline_array_impl!{16 => None, None, None, None, None, None, None, None,
+                         None, None, None, None, None, None, None, None}
+
+/// A flat map which keeps up to N key value pairs sorted in an inline
+/// array `A = [Option<(K, V)>, ..N]` and only allocates a sorted vector
+/// ~[(K, V)] once it grows past N pairs, e.g.
+/// `SmallFlatMap<uint, uint, [Option<(uint, uint)>, ..8]>`.
+pub struct SmallFlatMap<K, V, A> {
+    priv len: uint,
+    priv inline: A,
+    priv heap: Option<~[(K, V)]>,
+}
+
+impl<K: TotalOrd, V, A: InlineArray<(K, V)>> SmallFlatMap<K, V, A> {
+    /// Creates an empty SmallFlatMap.
+    pub fn new() -> SmallFlatMap<K, V, A> {
+        SmallFlatMap{len: 0, inline: InlineArray::empty(), heap: None}
+    }
+
+    /// Return the number of pairs which fit in the inline array.
+    pub fn inline_capacity(&self) -> uint {
+        self.inline.slots().len()
+    }
+
+    /// Return the capacity of the inline array, or of the vector once the
+    /// map has spilled to the heap.
+    pub fn capacity(&self) -> uint {
+        match self.heap {
+            Some(ref data) => data.capacity(),
+            None => self.inline_capacity(),
+        }
+    }
+
+    /// Return true if the map has outgrown its inline array and moved its
+    /// pairs to the heap.
+    pub fn spilled(&self) -> bool {
+        self.heap.is_some()
+    }
+
+    /// Return the index of the first key not less than `key` and whether
+    /// that key is equal to `key`.
+    fn search(&self, key: &K) -> (uint, bool) {
+        let index = match self.heap {
+            Some(ref data) => lower_bound_index(*data, key, &NaturalOrder),
+            None => {
+                lower_bound_by(self.inline.slots().slice_to(self.len), |slot| {
+                    match *slot {
+                        Some((ref k, _)) => k.cmp(key) == Less,
+                        None => false,
+                    }
+                })
+            }
+        };
+        let found = index < self.len() && {
+            let (k, _) = self.pair(index);
+            k.cmp(key) == Equal
+        };
+        (index, found)
+    }
+
+    fn pair<'a>(&'a self, i: uint) -> (&'a K, &'a V) {
+        match self.heap {
+            Some(ref data) => {
+                let (ref k, ref v) = data[i];
+                (k, v)
+            }
+            None => match self.inline.slots()[i] {
+                Some((ref k, ref v)) => (k, v),
+                None => fail2!("SmallFlatMap: empty inline slot {}", i),
+            }
+        }
+    }
+
+    fn pair_mut<'a>(&'a mut self, i: uint) -> (&'a K, &'a mut V) {
+        match self.heap {
+            Some(ref mut data) => {
+                let (ref k, ref mut v) = data[i];
+                (k, v)
+            }
+            None => match self.inline.mut_slots()[i] {
+                Some((ref k, ref mut v)) => (k, v),
+                None => fail2!("SmallFlatMap: empty inline slot {}", i),
+            }
+        }
+    }
+
+    fn insert_at(&mut self, i: uint, k: K, v: V) {
+        if self.heap.is_none() && self.len == self.inline_capacity() {
+            let mut data = vec::with_capacity(2 * self.len);
+            for slot in self.inline.mut_slots().mut_iter() {
+                data.push(slot.take_unwrap());
+            }
+            self.len = 0;
+            self.heap = Some(data);
+        }
+        match self.heap {
+            Some(ref mut data) => data.insert(i, (k, v)),
+            None => {
+                let len = self.len;
+                let slots = self.inline.mut_slots();
+                slots[len] = Some((k, v));
+                let mut j = len;
+                while j > i {
+                    slots.swap(j - 1, j);
+                    j -= 1;
+                }
+                self.len += 1;
+            }
+        }
+    }
+
+    fn remove_at(&mut self, i: uint) -> (K, V) {
+        match self.heap {
+            Some(ref mut data) => data.remove(i),
+            None => {
+                let len = self.len;
+                let slots = self.inline.mut_slots();
+                for j in range(i, len - 1) {
+                    slots.swap(j, j + 1);
+                }
+                self.len -= 1;
+                slots[len - 1].take_unwrap()
+            }
+        }
+    }
+
+    /// Modify and return the value corresponding to the key in the map, or
+    /// insert and return a new value if it doesn't exist.
+    pub fn mangle<'a,T>(&'a mut self, k: K, a: T, not_found: &fn(&K, T) -> V,
+                        found: &fn(&K, &mut V, T)) -> &'a mut V {
+        let (index, exists) = self.search(&k);
+        if exists {
+            let (key, val) = self.pair_mut(index);
+            found(key, val, a);
+        } else {
+            let v = not_found(&k, a);
+            self.insert_at(index, k, v);
+        }
+        let (_, val) = self.pair_mut(index);
+        val
+    }
+
+    /// Return the value corresponding to the key in the map, or insert
+    /// and return the value if it doesn't exist.
+    pub fn find_or_insert<'a>(&'a mut self, k: K, v: V) -> &'a mut V {
+        self.mangle(k, v, |_k, a| a, |_k,_v,_a| ())
+    }
+
+    /// Return the value corresponding to the key in the map, or create,
+    /// insert, and return a new value if it doesn't exist.
+    pub fn find_or_insert_with<'a>(&'a mut self, k: K, f: &fn(&K) -> V)
+                               -> &'a mut V {
+        self.mangle(k, (), |k,_a| f(k), |_k,_v,_a| ())
+    }
+
+    /// Insert a key-value pair into the map if the key is not already present.
+    /// Otherwise, modify the existing value for the key.
+    /// Returns the new or modified value for the key.
+    pub fn insert_or_update_with<'a>(&'a mut self, k: K, v: V,
+                                     f: &fn(&K, &mut V)) -> &'a mut V {
+        self.mangle(k, v, |_k,a| a, |k,v,_a| f(k,v))
+    }
+
+    /// An iterator visiting all key-value pairs in order.
+    /// Iterator element type is (&'a K, &'a V).
+    pub fn iter<'a>(&'a self) -> SmallFlatMapIterator<'a, K, V> {
+        let empty: &'a [(K, V)] = &[];
+        match self.heap {
+            Some(ref data) => {
+                SmallFlatMapIterator{inline: self.inline.slots().slice(0, 0).iter(),
+                                     heap: data.iter()}
+            }
+            None => {
+                SmallFlatMapIterator{inline: self.inline.slots().slice_to(self.len).iter(),
+                                     heap: empty.iter()}
+            }
+        }
+    }
+
+    /// An iterator visiting all key-value pairs in order,
+    /// with mutable references to the values.
+    /// Iterator element type is (&'a K, &'a mut V).
+    pub fn mut_iter<'a>(&'a mut self) -> SmallFlatMapMutIterator<'a, K, V> {
+        let len = self.len;
+        match self.heap {
+            Some(ref mut data) => {
+                SmallFlatMapMutIterator{inline: None, heap: Some(data.mut_iter())}
+            }
+            None => {
+                let slots = self.inline.mut_slots().mut_slice(0, len);
+                SmallFlatMapMutIterator{inline: Some(slots.mut_iter()), heap: None}
+            }
+        }
+    }
+
+    /// Creates a consuming iterator, that is, one that moves each key-value
+    /// pair out of the map in order. The map cannot be used after calling
+    /// this.
+    pub fn move_iter(self) -> SmallFlatMapMoveIterator<K, V, A> {
+        let SmallFlatMap{len, inline, heap} = self;
+        let heap = match heap {
+            Some(data) => Some(data.move_iter()),
+            None => None,
+        };
+        SmallFlatMapMoveIterator{inline: inline, front: 0, len: len, heap: heap}
+    }
+}
+
+impl<K: TotalOrd + fmt::Default, V, A: InlineArray<(K, V)>> SmallFlatMap<K, V, A> {
+    /// Retrieves a value for the given key, failing if the key is not
+    /// present.
+    pub fn get<'a>(&'a self, k: &K) -> &'a V {
+        match self.find(k) {
+            Some(v) => v,
+            None => fail2!("No entry found for key: {}", k),
+        }
+    }
+
+    /// Retrieves a (mutable) value for the given key, failing if the key
+    /// is not present.
+    pub fn get_mut<'a>(&'a mut self, k: &K) -> &'a mut V {
+        match self.find_mut(k) {
+            Some(v) => v,
+            None => fail2!("No entry found for key: {}", k),
+        }
+    }
+}
+
+impl<K: TotalOrd, V, A: InlineArray<(K, V)>> Container for SmallFlatMap<K, V, A> {
+    /// Return the number of elements in the map.
+    fn len(&self) -> uint {
+        match self.heap {
+            Some(ref data) => data.len(),
+            None => self.len,
+        }
+    }
+}
+
+impl<K: TotalOrd, V, A: InlineArray<(K, V)>> Mutable for SmallFlatMap<K, V, A> {
+    /// Clear the map, removing all items and releasing any heap storage.
+    fn clear(&mut self) {
+        for slot in self.inline.mut_slots().mut_iter() {
+            *slot = None;
+        }
+        self.len = 0;
+        self.heap = None;
+    }
+}
+
+impl<K: TotalOrd + Clone, V: Clone, A: InlineArray<(K, V)>> Clone for SmallFlatMap<K, V, A> {
+    fn clone(&self) -> SmallFlatMap<K, V, A> {
+        let mut map = SmallFlatMap::new();
+        for (k, v) in self.iter() {
+            let index = map.len();
+            map.insert_at(index, k.clone(), v.clone());
+        }
+        map
+    }
+}
+
+impl<K: TotalOrd, V, A: InlineArray<(K, V)>> FromIterator<(K, V)> for SmallFlatMap<K, V, A> {
+    fn from_iterator<T: Iterator<(K, V)>>(iter: &mut T) -> SmallFlatMap<K, V, A> {
+        let mut map = SmallFlatMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: TotalOrd, V, A: InlineArray<(K, V)>> Extendable<(K, V)> for SmallFlatMap<K, V, A> {
+    fn extend<T: Iterator<(K, V)>>(&mut self, iter: &mut T) {
+        for (k, v) in *iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K: TotalOrd, V, A: InlineArray<(K, V)>> Default for SmallFlatMap<K, V, A> {
+    fn default() -> SmallFlatMap<K, V, A> { SmallFlatMap::new() }
+}
+
+impl<K: Eq + TotalOrd, V: Eq, A: InlineArray<(K, V)>> Eq for SmallFlatMap<K, V, A> {
+    fn eq(&self, other: &SmallFlatMap<K, V, A>) -> bool {
+        self.len() == other.len() &&
+            self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+impl<K: TotalOrd, V, A: InlineArray<(K, V)>> Map<K, V> for SmallFlatMap<K, V, A> {
+    fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        match self.search(key) {
+            (index, true) => {
+                let (_, v) = self.pair(index);
+                Some(v)
+            }
+            (_, false) => None,
+        }
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        let (_, found) = self.search(key);
+        found
+    }
+}
+
+impl<K: TotalOrd, V, A: InlineArray<(K, V)>> MutableMap<K, V> for SmallFlatMap<K, V, A> {
+    fn find_mut<'a>(&'a mut self, key: &K) -> Option<&'a mut V> {
+        match self.search(key) {
+            (index, true) => {
+                let (_, v) = self.pair_mut(index);
+                Some(v)
+            }
+            (_, false) => None,
+        }
+    }
+
+    fn swap(&mut self, k: K, v: V) -> Option<V> {
+        match self.search(&k) {
+            (index, true) => {
+                let (_, val) = self.pair_mut(index);
+                Some(replace(val, v))
+            }
+            (index, false) => {
+                self.insert_at(index, k, v);
+                None
+            }
+        }
+    }
+
+    fn pop(&mut self, key: &K) -> Option<V> {
+        match self.search(key) {
+            (index, true) => {
+                let (_, v) = self.remove_at(index);
+                Some(v)
+            }
+            (_, false) => None,
+        }
+    }
+}
+
+/// SmallFlatMap iterator
+pub struct SmallFlatMapIterator<'self, K, V> {
+    priv inline: vec::VecIterator<'self, Option<(K, V)>>,
+    priv heap: vec::VecIterator<'self, (K, V)>,
+}
+
+impl<'self, K, V> Iterator<(&'self K, &'self V)> for SmallFlatMapIterator<'self, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<(&'self K, &'self V)> {
+        match self.inline.next() {
+            Some(&Some((ref k, ref v))) => return Some((k, v)),
+            _ => {}
+        }
+        match self.heap.next() {
+            Some(&(ref k, ref v)) => Some((k, v)),
+            None => None
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        let (a, _) = self.inline.size_hint();
+        let (b, _) = self.heap.size_hint();
+        (a + b, Some(a + b))
+    }
+}
+
+/// SmallFlatMap mutable values iterator
+pub struct SmallFlatMapMutIterator<'self, K, V> {
+    priv inline: Option<vec::VecMutIterator<'self, Option<(K, V)>>>,
+    priv heap: Option<vec::VecMutIterator<'self, (K, V)>>,
+}
+
+impl<'self, K, V> Iterator<(&'self K, &'self mut V)> for SmallFlatMapMutIterator<'self, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<(&'self K, &'self mut V)> {
+        match self.inline {
+            Some(ref mut iter) => match iter.next() {
+                Some(&Some((ref k, ref mut v))) => Some((k, v)),
+                _ => None
+            },
+            None => match self.heap {
+                Some(ref mut iter) => match iter.next() {
+                    Some(&(ref k, ref mut v)) => Some((k, v)),
+                    None => None
+                },
+                None => None
+            }
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        match (&self.inline, &self.heap) {
+            (&Some(ref iter), _) => iter.size_hint(),
+            (_, &Some(ref iter)) => iter.size_hint(),
+            _ => (0, Some(0)),
+        }
+    }
+}
+
+/// SmallFlatMap move iterator
+pub struct SmallFlatMapMoveIterator<K, V, A> {
+    priv inline: A,
+    // the inline pairs left are in the slots [front, len)
+    priv front: uint,
+    priv len: uint,
+    priv heap: Option<vec::MoveIterator<(K, V)>>,
+}
+
+impl<K, V, A: InlineArray<(K, V)>> Iterator<(K, V)> for SmallFlatMapMoveIterator<K, V, A> {
+    #[inline]
+    fn next(&mut self) -> Option<(K, V)> {
+        match self.heap {
+            Some(ref mut iter) => iter.next(),
+            None => {
+                if self.front == self.len {
+                    None
+                } else {
+                    self.front += 1;
+                    self.inline.mut_slots()[self.front - 1].take()
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        match self.heap {
+            Some(ref iter) => iter.size_hint(),
+            None => (self.len - self.front, Some(self.len - self.front)),
+        }
+    }
+}
+
+/// Smallest staging vector a BufferedFlatMap will merge when it chooses
+/// the threshold itself.
+static MIN_STAGING_THRESHOLD: uint = 32;
+
+/// Integer square root, rounded down.
+fn isqrt(n: uint) -> uint {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+/// A flat map for insert heavy workloads. The pairs live in one sorted
+/// vector ~[(K, V)] plus an unsorted staging tail. New keys are pushed onto
+/// the tail, and once it holds more than `t` pairs it is sorted and merged
+/// into the sorted vector in one linear pass, instead of shifting the
+/// vector on every insert.
+///
+/// Lookups binary search the sorted vector and scan the tail, so they cost
+/// O(log n + t). An insert pays for that lookup plus an amortized O(n / t)
+/// share of the merges. Unless a fixed threshold is given `t` tracks
+/// `sqrt(n)`, which makes both inserts and lookups O(sqrt(n)); `flush`
+/// brings lookups back to O(log n) once the inserts are done.
+#[deriving(Clone)]
+pub struct BufferedFlatMap<K, V> {
+    priv data: ~[(K, V)],
+    priv staging: ~[(K, V)],
+    priv threshold: Option<uint>,
+}
+
+impl<K: TotalOrd, V> BufferedFlatMap<K, V> {
+    /// Creates an empty BufferedFlatMap.
+    pub fn new() -> BufferedFlatMap<K, V> {
+        BufferedFlatMap::with_capacity(0)
+    }
+
+    /// Create an empty BufferedFlatMap with space for at least `n` elements.
+    pub fn with_capacity(capacity: uint) -> BufferedFlatMap<K, V> {
+        BufferedFlatMap{data: vec::with_capacity(capacity), staging: ~[], threshold: None}
+    }
+
+    /// Create an empty BufferedFlatMap which merges its staging tail once
+    /// it holds more than `threshold` pairs.
+    pub fn with_threshold(threshold: uint) -> BufferedFlatMap<K, V> {
+        BufferedFlatMap{data: ~[], staging: ~[], threshold: Some(threshold)}
+    }
+
+    /// Return the number of pairs waiting in the staging tail.
+    pub fn staged(&self) -> uint {
+        self.staging.len()
+    }
+
+    /// Return the number of elements the sorted vector can hold without
+    /// reallocating.
+    pub fn capacity(&self) -> uint {
+        self.data.capacity()
+    }
+
+    fn staging_limit(&self) -> uint {
+        match self.threshold {
+            Some(t) => t,
+            None => cmp::max(MIN_STAGING_THRESHOLD, isqrt(self.data.len())),
+        }
+    }
+
+    #[inline]
+    fn find_index(&self, key: &K) -> Option<uint> {
+        self.data.bsearch(|&(ref k,_)|{k.cmp(key)})
+    }
+
+    #[inline]
+    fn find_staged(&self, key: &K) -> Option<uint> {
+        self.staging.iter().position(|&(ref k, _)| k.cmp(key) == Equal)
+    }
+
+    /// Sort the staging tail and merge it into the sorted vector.
+    pub fn flush(&mut self) {
+        if self.staging.is_empty() {
+            return;
+        }
+        let cmp = |a: &K, b: &K| a.cmp(b);
+        let staging = sort_by_key(replace(&mut self.staging, ~[]), cmp);
+        let data = replace(&mut self.data, ~[]);
+        self.data = merge_runs(data, staging, cmp);
+    }
+
+    /// Merge the staging tail and convert into a FlatMap.
+    pub fn into_flatmap(self) -> FlatMap<K, V> {
+        let mut map = self;
+        map.flush();
+        let BufferedFlatMap{data, _} = map;
+        FlatMap::from_data(data)
+    }
+
+    /// An iterator visiting all key-value pairs in order, merging the
+    /// staging tail on the fly.
+    /// Iterator element type is (&'a K, &'a V).
+    pub fn iter<'a>(&'a self) -> BufferedFlatMapIterator<'a, K, V> {
+        let staged: ~[(&'a K, &'a V)] = self.staging.iter().map(|&(ref k, ref v)| (k, v)).collect();
+        let staged = sort_by_key(staged, |a, b| (*a).cmp(*b));
+        let mut main = FlatMapIterator{iter: self.data.iter()};
+        let mut staged = staged.move_iter();
+        let main_head = main.next();
+        let staged_head = staged.next();
+        BufferedFlatMapIterator{main: main, staged: staged,
+                                main_head: main_head, staged_head: staged_head}
+    }
+}
+
+impl<K: TotalOrd + fmt::Default, V> BufferedFlatMap<K, V> {
+    /// Retrieves a value for the given key, failing if the key is not
+    /// present.
+    pub fn get<'a>(&'a self, k: &K) -> &'a V {
+        match self.find(k) {
+            Some(v) => v,
+            None => fail2!("No entry found for key: {}", k),
+        }
+    }
+
+    /// Retrieves a (mutable) value for the given key, failing if the key
+    /// is not present.
+    pub fn get_mut<'a>(&'a mut self, k: &K) -> &'a mut V {
+        match self.find_mut(k) {
+            Some(v) => v,
+            None => fail2!("No entry found for key: {}", k),
+        }
+    }
+}
+
+impl<K, V> Container for BufferedFlatMap<K, V> {
+    /// Return the number of elements in the map.
+    fn len(&self) -> uint {
+        self.data.len() + self.staging.len()
+    }
+}
+
+impl<K, V> Mutable for BufferedFlatMap<K, V> {
+    /// Clear the map, removing all items.
+    fn clear(&mut self) {
+        self.data.clear();
+        self.staging.clear();
+    }
+}
+
+impl<K: TotalOrd, V> FromIterator<(K, V)> for BufferedFlatMap<K, V> {
+    fn from_iterator<T: Iterator<(K, V)>>(iter: &mut T) -> BufferedFlatMap<K, V> {
+        let data = FlatMap::from_iter_with(iter, KeepLast).into_data();
+        BufferedFlatMap{data: data, staging: ~[], threshold: None}
+    }
+}
+
+impl<K: TotalOrd, V> Extendable<(K, V)> for BufferedFlatMap<K, V> {
+    fn extend<T: Iterator<(K, V)>>(&mut self, iter: &mut T) {
+        for (k, v) in *iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K: TotalOrd, V> Default for BufferedFlatMap<K, V> {
+    fn default() -> BufferedFlatMap<K, V> { BufferedFlatMap::new() }
+}
+
+impl<K: TotalOrd, V> Map<K, V> for BufferedFlatMap<K, V> {
+    fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        let idx = match self.find_index(key) {
+            Some(idx) => idx,
+            None => match self.find_staged(key) {
+                Some(idx) => {
+                    let (_, ref v) = self.staging[idx];
+                    return Some(v);
+                }
+                None => return None,
+            }
+        };
+        let (_, ref v) = self.data[idx];
+        Some(v)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.find_index(key).is_some() || self.find_staged(key).is_some()
+    }
+}
+
+impl<K: TotalOrd, V> MutableMap<K, V> for BufferedFlatMap<K, V> {
+    fn find_mut<'a>(&'a mut self, key: &K) -> Option<&'a mut V> {
+        match self.find_index(key) {
+            Some(idx) => {
+                let (_, ref mut v) = self.data[idx];
+                return Some(v);
+            }
+            None => {}
+        }
+        match self.find_staged(key) {
+            Some(idx) => {
+                let (_, ref mut v) = self.staging[idx];
+                Some(v)
+            }
+            None => None,
+        }
+    }
+
+    fn swap(&mut self, k: K, v: V) -> Option<V> {
+        match self.find_mut(&k) {
+            Some(val) => return Some(replace(val, v)),
+            None => {}
+        }
+        self.staging.push((k, v));
+        if self.staging.len() > self.staging_limit() {
+            self.flush();
+        }
+        None
+    }
+
+    fn pop(&mut self, key: &K) -> Option<V> {
+        match self.find_index(key) {
+            Some(idx) => {
+                let (_, v) = self.data.remove(idx);
+                return Some(v);
+            }
+            None => {}
+        }
+        match self.find_staged(key) {
+            Some(idx) => {
+                let (_, v) = self.staging.swap_remove(idx);
+                Some(v)
+            }
+            None => None,
+        }
+    }
+}
+
+/// BufferedFlatMap iterator
+pub struct BufferedFlatMapIterator<'self, K, V> {
+    priv main: FlatMapIterator<'self, K, V>,
+    priv staged: vec::MoveIterator<(&'self K, &'self V)>,
+    priv main_head: Option<(&'self K, &'self V)>,
+    priv staged_head: Option<(&'self K, &'self V)>,
+}
+
+impl<'self, K: TotalOrd, V> Iterator<(&'self K, &'self V)> for BufferedFlatMapIterator<'self, K, V> {
+    fn next(&mut self) -> Option<(&'self K, &'self V)> {
+        let take_main = match (self.main_head, self.staged_head) {
+            (None, None) => return None,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some((a, _)), Some((b, _))) => a.cmp(b) != Greater,
+        };
+        if take_main {
+            replace(&mut self.main_head, self.main.next())
+        } else {
+            replace(&mut self.staged_head, self.staged.next())
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        let (a, _) = self.main.size_hint();
+        let (b, _) = self.staged.size_hint();
+        let heads = (if self.main_head.is_some() { 1 } else { 0 }) +
+            (if self.staged_head.is_some() { 1 } else { 0 });
+        (a + b + heads, Some(a + b + heads))
+    }
+}
+
+/// The largest number of pairs in a chunk of a PersistentFlatMap. A full
+/// chunk is split in two when a pair is added to it.
+static PERSISTENT_CHUNK_SIZE: uint = 64;
+
+/// A chunk of a PersistentFlatMap, shared by reference counting between a
+/// map and its snapshots. Unlike `Rc` it can tell when it is the only
+/// reference, so a change to an unshared chunk is made in place. The count
+/// is atomic, so a snapshot can be sent to another task.
+struct SharedChunk<K, V> {
+    priv ptr: *mut SharedChunkBox<K, V>,
+}
+
+struct SharedChunkBox<K, V> {
+    count: AtomicUint,
+    pairs: ~[(K, V)],
+}
+
+impl<K, V> SharedChunk<K, V> {
+    fn new(pairs: ~[(K, V)]) -> SharedChunk<K, V> {
+        unsafe {
+            SharedChunk{ptr: cast::transmute(~SharedChunkBox{count: AtomicUint::new(1),
+                                                             pairs: pairs})}
+        }
+    }
+
+    #[inline]
+    fn get<'a>(&'a self) -> &'a [(K, V)] {
+        unsafe {
+            let chunk: &'a SharedChunkBox<K, V> = cast::transmute(self.ptr);
+            chunk.pairs.as_slice()
+        }
+    }
+
+    // Only this handle can add a reference to a chunk it holds alone, so
+    // a count of one stays one. The Acquire load orders any reads through
+    // a handle which was just dropped before the caller's writes.
+    #[inline]
+    fn is_shared(&self) -> bool {
+        unsafe { (*self.ptr).count.load(Acquire) > 1 }
+    }
+}
+
+impl<K: Clone, V: Clone> SharedChunk<K, V> {
+    /// Return the pairs for changing, first copying them if another map
+    /// shares the chunk.
+    fn get_mut<'a>(&'a mut self) -> &'a mut ~[(K, V)] {
+        if self.is_shared() {
+            *self = SharedChunk::new(self.get().to_owned());
+        }
+        unsafe { &mut (*self.ptr).pairs }
+    }
+}
+
+impl<K, V> Clone for SharedChunk<K, V> {
+    fn clone(&self) -> SharedChunk<K, V> {
+        unsafe {
+            (*self.ptr).count.fetch_add(1, Relaxed);
+        }
+        SharedChunk{ptr: self.ptr}
+    }
+}
+
+#[unsafe_destructor]
+impl<K, V> Drop for SharedChunk<K, V> {
+    fn drop(&mut self) {
+        unsafe {
+            if (*self.ptr).count.fetch_sub(1, SeqCst) == 1 {
+                let _: ~SharedChunkBox<K, V> = cast::transmute(self.ptr);
+            }
+        }
+    }
+}
+
+/// A sorted map whose storage is split into chunks shared by reference
+/// counting, so `clone` takes a cheap snapshot which later changes do not
+/// affect. A change copies only the chunk it touches, and only if a
+/// snapshot still shares it. Snapshots of a map with sendable keys and
+/// values can be sent to other tasks.
+#[deriving(Clone)]
+pub struct PersistentFlatMap<K, V> {
+    priv chunks: ~[SharedChunk<K, V>],
+    priv len: uint,
+}
+
+impl<K: TotalOrd + Clone + Freeze, V: Clone + Freeze> PersistentFlatMap<K, V> {
+    /// Creates an empty PersistentFlatMap.
+    pub fn new() -> PersistentFlatMap<K, V> {
+        PersistentFlatMap{chunks: ~[], len: 0}
+    }
+
+    /// Create a PersistentFlatMap with the pairs of a FlatMap in natural
+    /// order.
+    pub fn from_flatmap(map: FlatMap<K, V>) -> PersistentFlatMap<K, V> {
+        let data = map.into_data();
+        let len = data.len();
+        let half = PERSISTENT_CHUNK_SIZE / 2;
+        let mut chunks = ~[];
+        let mut chunk = vec::with_capacity(half);
+        for pair in data.move_iter() {
+            chunk.push(pair);
+            if chunk.len() == half {
+                chunks.push(SharedChunk::new(replace(&mut chunk, vec::with_capacity(half))));
+            }
+        }
+        if !chunk.is_empty() {
+            chunks.push(SharedChunk::new(chunk));
+        }
+        PersistentFlatMap{chunks: chunks, len: len}
+    }
+
+    /// Return the number of chunks the pairs are stored in.
+    pub fn chunk_count(&self) -> uint {
+        self.chunks.len()
+    }
+
+    /// Return the chunk which holds `key` if it is in the map, which is the
+    /// first chunk whose last key is not less than `key`.
+    #[inline]
+    fn chunk_index(&self, key: &K) -> uint {
+        lower_bound_by(self.chunks, |c| {
+            let c = c.get();
+            key_at(c, c.len() - 1).cmp(key) == Less
+        })
+    }
+
+    /// Insert a key-value pair into the map. An existing value for a key is
+    /// replaced by the new value. Return true if the key did not already
+    /// exist in the map.
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        self.swap(key, value).is_none()
+    }
+
+    /// Insert a key-value pair into the map, returning the old value if
+    /// the key was already present.
+    pub fn swap(&mut self, key: K, value: V) -> Option<V> {
+        if self.chunks.is_empty() {
+            self.chunks.push(SharedChunk::new(~[(key, value)]));
+            self.len = 1;
+            return None;
+        }
+        // a key past the last chunk goes at the end of the last chunk
+        let i = cmp::min(self.chunk_index(&key), self.chunks.len() - 1);
+        let tail = {
+            let chunk = self.chunks[i].get_mut();
+            let index = lower_bound_index(*chunk, &key, &NaturalOrder);
+            if index < chunk.len() && key_at(*chunk, index).cmp(&key) == Equal {
+                let (_, ref mut v) = chunk[index];
+                return Some(replace(v, value));
+            }
+            chunk.insert(index, (key, value));
+            if chunk.len() > PERSISTENT_CHUNK_SIZE {
+                let mut tail = vec::with_capacity(chunk.len() / 2 + 1);
+                while chunk.len() > PERSISTENT_CHUNK_SIZE / 2 {
+                    tail.push(chunk.pop());
+                }
+                tail.reverse();
+                Some(tail)
+            } else {
+                None
+            }
+        };
+        self.len += 1;
+        match tail {
+            Some(tail) => self.chunks.insert(i + 1, SharedChunk::new(tail)),
+            None => (),
+        }
+        None
+    }
+
+    /// Remove a key-value pair from the map, returning the value if the key
+    /// was present.
+    pub fn pop(&mut self, key: &K) -> Option<V> {
+        let i = self.chunk_index(key);
+        if i == self.chunks.len() {
+            return None;
+        }
+        let index = match self.chunks[i].get().bsearch(|&(ref k, _)| k.cmp(key)) {
+            None => return None,
+            Some(index) => index,
+        };
+        let (_, v) = self.chunks[i].get_mut().remove(index);
+        self.len -= 1;
+        if self.chunks[i].get().is_empty() {
+            self.chunks.remove(i);
+        }
+        Some(v)
+    }
+
+    /// Remove a key-value pair from the map. Return true if the key was
+    /// present in the map, otherwise false.
+    pub fn remove(&mut self, key: &K) -> bool {
+        self.pop(key).is_some()
+    }
+
+    /// Modify the value for `key` with `f`, copying the chunk which holds
+    /// it only if a snapshot shares it. Return true if the key was present
+    /// in the map.
+    pub fn modify(&mut self, key: &K, f: &fn(&K, &mut V)) -> bool {
+        let i = self.chunk_index(key);
+        if i == self.chunks.len() {
+            return false;
+        }
+        let index = match self.chunks[i].get().bsearch(|&(ref k, _)| k.cmp(key)) {
+            None => return false,
+            Some(index) => index,
+        };
+        let chunk = self.chunks[i].get_mut();
+        let (ref k, ref mut v) = chunk[index];
+        f(k, v);
+        true
+    }
+
+    /// Copy the pairs into a FlatMap.
+    pub fn to_flatmap(&self) -> FlatMap<K, V> {
+        let mut data = vec::with_capacity(self.len);
+        for chunk in self.chunks.iter() {
+            data.push_all(chunk.get());
+        }
+        FlatMap::from_data(data)
+    }
+
+    /// An iterator visiting all key-value pairs in order.
+    /// Iterator element type is (&'a K, &'a V).
+    pub fn iter<'a>(&'a self) -> PersistentFlatMapIterator<'a, K, V> {
+        PersistentFlatMapIterator{chunks: self.chunks.iter(), chunk: None}
+    }
+}
+
+impl<K, V> Container for PersistentFlatMap<K, V> {
+    /// Return the number of elements in the map.
+    fn len(&self) -> uint {
+        self.len
+    }
+}
+
+impl<K, V> Mutable for PersistentFlatMap<K, V> {
+    /// Clear the map, removing all key-value pairs. Snapshots keep theirs.
+    fn clear(&mut self) {
+        self.chunks.clear();
+        self.len = 0;
+    }
+}
+
+impl<K: TotalOrd + Clone + Freeze, V: Clone + Freeze> Map<K, V> for PersistentFlatMap<K, V> {
+    fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        let i = self.chunk_index(key);
+        if i == self.chunks.len() {
+            return None;
+        }
+        let chunk = self.chunks[i].get();
+        match chunk.bsearch(|&(ref k, _)| k.cmp(key)) {
+            None => None,
+            Some(index) => {
+                let (_, ref v) = chunk[index];
+                Some(v)
+            }
+        }
+    }
+}
+
+impl<K: TotalOrd + Clone + Freeze, V: Clone + Freeze> FromIterator<(K, V)> for PersistentFlatMap<K, V> {
+    fn from_iterator<T: Iterator<(K, V)>>(iter: &mut T) -> PersistentFlatMap<K, V> {
+        PersistentFlatMap::from_flatmap(FlatMap::from_iter_with(iter, KeepLast))
+    }
+}
+
+impl<K: TotalOrd + Clone + Freeze, V: Clone + Freeze> Default for PersistentFlatMap<K, V> {
+    fn default() -> PersistentFlatMap<K, V> { PersistentFlatMap::new() }
+}
+
+/// PersistentFlatMap iterator
+pub struct PersistentFlatMapIterator<'self, K, V> {
+    priv chunks: vec::VecIterator<'self, SharedChunk<K, V>>,
+    priv chunk: Option<vec::VecIterator<'self, (K, V)>>,
+}
+
+impl<'self, K: Freeze, V: Freeze> Iterator<(&'self K, &'self V)> for PersistentFlatMapIterator<'self, K, V> {
+    fn next(&mut self) -> Option<(&'self K, &'self V)> {
+        loop {
+            match self.chunk {
+                Some(ref mut iter) => match iter.next() {
+                    Some(&(ref k, ref v)) => return Some((k, v)),
+                    None => (),
+                },
+                None => (),
+            }
+            match self.chunks.next() {
+                Some(chunk) => self.chunk = Some(chunk.get().iter()),
+                None => return None,
+            }
+        }
+    }
+}
+
+/// A sorted multimap which stores key value pairs in a sorted vector
+/// ~[(K, V)] like FlatMap, but allows equal keys. Values for equal keys are
+/// kept in the order they were inserted.
+#[deriving(Clone, Eq)]
+pub struct FlatMultiMap<K, V> {
+    priv data: ~[(K, V)],
+}
+
+impl<K: TotalOrd, V> FlatMultiMap<K, V> {
+    /// Creates an empty FlatMultiMap.
+    pub fn new() -> FlatMultiMap<K, V> {
+        FlatMultiMap::with_capacity(0)
+    }
+
+    /// Create an empty FlatMultiMap with space for at least `n` elements.
+    pub fn with_capacity(capacity: uint) -> FlatMultiMap<K, V> {
+        FlatMultiMap{data: vec::with_capacity(capacity)}
+    }
+
+    /// Return the capacity of the underlying vector.
+    pub fn capacity(&self) -> uint {
+        self.data.capacity()
+    }
+
+    /// Insert a key-value pair after any pairs with an equal key.
+    pub fn insert(&mut self, key: K, value: V) {
+        let index = upper_bound_index(self.data, &key, &NaturalOrder);
+        self.data.insert(index, (key, value));
+    }
+
+    #[inline]
+    fn equal_indices(&self, key: &K) -> (uint, uint) {
+        let start = lower_bound_index(self.data, key, &NaturalOrder);
+        let len = lower_bound_by(self.data.slice_from(start), |&(ref k, _)| k.cmp(key) == Equal);
+        (start, start + len)
+    }
+
+    /// An iterator visiting the pairs whose key is equal to `key`, in the
+    /// order they were inserted.
+    pub fn equal_range<'a>(&'a self, key: &K) -> FlatMapIterator<'a, K, V> {
+        let (start, end) = self.equal_indices(key);
+        FlatMapIterator{iter: self.data.slice(start, end).iter()}
+    }
+
+    /// Return the number of values for `key`.
+    pub fn count(&self, key: &K) -> uint {
+        let (start, end) = self.equal_indices(key);
+        end - start
+    }
+
+    /// Return true if the map contains a value for `key`.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.count(key) > 0
+    }
+
+    /// Remove all pairs whose key is equal to `key`, returning their values
+    /// in the order they were inserted.
+    pub fn remove_all(&mut self, key: &K) -> ~[V] {
+        let (start, end) = self.equal_indices(key);
+        let mut tail = vec::with_capacity(self.data.len() - end);
+        while self.data.len() > end {
+            tail.push(self.data.pop());
+        }
+        let mut values = vec::with_capacity(end - start);
+        while self.data.len() > start {
+            let (_, v) = self.data.pop();
+            values.push(v);
+        }
+        tail.reverse();
+        self.data.push_all_move(tail);
+        values.reverse();
+        values
+    }
+
+    /// Remove the earliest inserted pair whose key is equal to `key`,
+    /// returning its value.
+    pub fn remove_one(&mut self, key: &K) -> Option<V> {
+        let (start, end) = self.equal_indices(key);
+        if start < end {
+            let (_, v) = self.data.remove(start);
+            Some(v)
+        } else {
+            None
+        }
+    }
+
+    /// An iterator visiting all key-value pairs in order.
+    /// Iterator element type is (&'a K, &'a V).
+    pub fn iter<'a>(&'a self) -> FlatMapIterator<'a, K, V> {
+        FlatMapIterator{iter: self.data.iter()}
+    }
+
+    /// An iterator visiting each distinct key in order together with an
+    /// iterator over its values.
+    pub fn group_iter<'a>(&'a self) -> FlatMultiMapGroups<'a, K, V> {
+        FlatMultiMapGroups{data: self.data}
+    }
+}
+
+impl<K, V> Container for FlatMultiMap<K, V> {
+    /// Return the number of elements in the map.
+    fn len(&self) -> uint {
+        self.data.len()
+    }
+}
+
+impl<K, V> Mutable for FlatMultiMap<K, V> {
+    /// Clear the map, removing all key-value pairs.
+    fn clear(&mut self) {
+        self.data.clear();
+    }
+}
+
+impl<K: TotalOrd, V> FromIterator<(K, V)> for FlatMultiMap<K, V> {
+    fn from_iterator<T: Iterator<(K, V)>>(iter: &mut T) -> FlatMultiMap<K, V> {
+        let mut map = FlatMultiMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: TotalOrd, V> Extendable<(K, V)> for FlatMultiMap<K, V> {
+    fn extend<T: Iterator<(K, V)>>(&mut self, iter: &mut T) {
+        let cmp = |a: &K, b: &K| a.cmp(b);
+        let data: ~[(K, V)] = iter.collect();
+        // both the sort and the merge are stable, so values for equal keys
+        // stay in insertion order
+        let data = sort_by_key(data, cmp);
+        let old = replace(&mut self.data, ~[]);
+        self.data = merge_runs(old, data, cmp);
+    }
+}
+
+impl<K: TotalOrd, V> Default for FlatMultiMap<K, V> {
+    fn default() -> FlatMultiMap<K, V> { FlatMultiMap::new() }
+}
+
+/// FlatMultiMap iterator over the runs of equal keys
+pub struct FlatMultiMapGroups<'self, K, V> {
+    priv data: &'self [(K, V)],
+}
+
+impl<'self, K: TotalOrd, V> Iterator<(&'self K, FlatMultiMapValues<'self, K, V>)>
+        for FlatMultiMapGroups<'self, K, V> {
+    fn next(&mut self) -> Option<(&'self K, FlatMultiMapValues<'self, K, V>)> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let data = self.data;
+        let key = key_at(data, 0);
+        let len = lower_bound_by(data, |&(ref k, _)| k.cmp(key) == Equal);
+        self.data = data.slice_from(len);
+        Some((key, FlatMultiMapValues{iter: data.slice_to(len).iter()}))
+    }
+}
+
+/// FlatMultiMap iterator over the values of one key
+pub struct FlatMultiMapValues<'self, K, V> {
+    priv iter: vec::VecIterator<'self, (K, V)>,
+}
+
+impl<'self, K, V> Iterator<&'self V> for FlatMultiMapValues<'self, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<&'self V> {
+        match self.iter.next() {
+            Some(&(_, ref v)) => Some(v),
+            None => None
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        return self.iter.size_hint();
+    }
+}
+
+/// A map from half-open ranges of keys to values, for tables such as IP
+/// ranges or memory regions. The ranges are stored as (start, end, value)
+/// in a vector sorted by start, like FlatMap, and never overlap. Adjacent
+/// ranges with equal values are merged into one.
+#[deriving(Clone, Eq)]
+pub struct FlatIntervalMap<K, V> {
+    priv data: ~[(K, K, V)],
+}
+
+impl<K: TotalOrd, V> FlatIntervalMap<K, V> {
+    /// Creates an empty FlatIntervalMap.
+    pub fn new() -> FlatIntervalMap<K, V> {
+        FlatIntervalMap{data: ~[]}
+    }
+
+    /// Create an empty FlatIntervalMap with space for at least `n` ranges.
+    pub fn with_capacity(capacity: uint) -> FlatIntervalMap<K, V> {
+        FlatIntervalMap{data: vec::with_capacity(capacity)}
+    }
+
+    /// Return the indices of the ranges which overlap `start..end`.
+    fn overlap_indices(&self, start: &K, end: &K) -> (uint, uint) {
+        let i = lower_bound_by(self.data, |&(_, ref e, _)| e.cmp(start) != Greater);
+        if start.cmp(end) != Less {
+            return (i, i);
+        }
+        let rest = self.data.slice_from(i);
+        (i, i + lower_bound_by(rest, |&(ref s, _, _)| s.cmp(end) == Less))
+    }
+
+    /// Return the value of the range which contains `key`.
+    pub fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        let i = lower_bound_by(self.data, |&(ref s, _, _)| s.cmp(key) != Greater);
+        if i > 0 {
+            let (_, ref e, ref v) = self.data[i - 1];
+            if key.cmp(e) == Less {
+                return Some(v);
+            }
+        }
+        None
+    }
+
+    /// An iterator visiting all ranges in order.
+    /// Iterator element type is (&'a K, &'a K, &'a V).
+    pub fn iter<'a>(&'a self) -> FlatIntervalMapIterator<'a, K, V> {
+        FlatIntervalMapIterator{iter: self.data.iter()}
+    }
+
+    /// An iterator visiting the ranges which overlap `start..end`, in
+    /// order.
+    pub fn overlapping<'a>(&'a self, start: &K, end: &K) -> FlatIntervalMapIterator<'a, K, V> {
+        let (i, j) = self.overlap_indices(start, end);
+        FlatIntervalMapIterator{iter: self.data.slice(i, j).iter()}
+    }
+
+    /// Replace the ranges from `lo` to `hi` with `pieces`.
+    fn splice(&mut self, lo: uint, hi: uint, pieces: ~[(K, K, V)]) {
+        let mut tail = vec::with_capacity(self.data.len() - hi);
+        while self.data.len() > hi {
+            tail.push(self.data.pop());
+        }
+        self.data.truncate(lo);
+        self.data.push_all_move(pieces);
+        tail.reverse();
+        self.data.push_all_move(tail);
+    }
+}
+
+impl<K: TotalOrd + Clone, V: Clone + Eq> FlatIntervalMap<K, V> {
+    /// Return the parts of the ranges from `i` to `j` which lie before
+    /// `start` and after `end`, when those ranges overlap `start..end`.
+    fn clip(&self, i: uint, j: uint, start: &K, end: &K)
+            -> (Option<(K, K, V)>, Option<(K, K, V)>) {
+        if i == j {
+            return (None, None);
+        }
+        let left = match self.data[i] {
+            (ref s, _, ref v) if s.cmp(start) == Less => {
+                Some((s.clone(), start.clone(), v.clone()))
+            }
+            _ => None,
+        };
+        let right = match self.data[j - 1] {
+            (_, ref e, ref v) if e.cmp(end) == Greater => {
+                Some((end.clone(), e.clone(), v.clone()))
+            }
+            _ => None,
+        };
+        (left, right)
+    }
+
+    /// Map the keys from `start` up to but not including `end` to `value`,
+    /// splitting or replacing the ranges it overlaps. Does nothing if
+    /// `start` is not less than `end`.
+    pub fn insert_range(&mut self, start: K, end: K, value: V) {
+        if start.cmp(&end) != Less {
+            return;
+        }
+        let (i, j) = self.overlap_indices(&start, &end);
+        let (left, right) = self.clip(i, j, &start, &end);
+        let mut pieces = ~[];
+        match left {
+            Some(piece) => pieces.push(piece),
+            None => (),
+        }
+        let index = i + pieces.len();
+        pieces.push((start, end, value));
+        match right {
+            Some(piece) => pieces.push(piece),
+            None => (),
+        }
+        self.splice(i, j, pieces);
+        self.coalesce(index);
+        if index > 0 {
+            self.coalesce(index - 1);
+        }
+    }
+
+    /// Remove the keys from `start` up to but not including `end` from the
+    /// map, splitting the ranges it overlaps.
+    pub fn remove_range(&mut self, start: &K, end: &K) {
+        if start.cmp(end) != Less {
+            return;
+        }
+        let (i, j) = self.overlap_indices(start, end);
+        let (left, right) = self.clip(i, j, start, end);
+        let mut pieces = ~[];
+        match left {
+            Some(piece) => pieces.push(piece),
+            None => (),
+        }
+        match right {
+            Some(piece) => pieces.push(piece),
+            None => (),
+        }
+        self.splice(i, j, pieces);
+    }
+
+    /// Merge the range at `index` with the next one if they touch and
+    /// have equal values.
+    fn coalesce(&mut self, index: uint) {
+        if index + 1 >= self.data.len() {
+            return;
+        }
+        let merge = {
+            let (_, ref end, ref a) = self.data[index];
+            let (ref start, _, ref b) = self.data[index + 1];
+            end.cmp(start) == Equal && *a == *b
+        };
+        if merge {
+            let (_, end, _) = self.data.remove(index + 1);
+            let (_, ref mut e, _) = self.data[index];
+            *e = end;
+        }
+    }
+}
+
+impl<K, V> Container for FlatIntervalMap<K, V> {
+    /// Return the number of ranges in the map.
+    fn len(&self) -> uint {
+        self.data.len()
+    }
+}
+
+impl<K, V> Mutable for FlatIntervalMap<K, V> {
+    /// Clear the map, removing all ranges.
+    fn clear(&mut self) {
+        self.data.clear();
+    }
+}
+
+impl<K: TotalOrd, V> Default for FlatIntervalMap<K, V> {
+    fn default() -> FlatIntervalMap<K, V> { FlatIntervalMap::new() }
+}
+
+/// FlatIntervalMap iterator
+pub struct FlatIntervalMapIterator<'self, K, V> {
+    priv iter: vec::VecIterator<'self, (K, K, V)>,
+}
+
+impl<'self, K, V> Iterator<(&'self K, &'self K, &'self V)> for FlatIntervalMapIterator<'self, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<(&'self K, &'self K, &'self V)> {
+        match self.iter.next() {
+            Some(&(ref s, ref e, ref v)) => Some((s, e, v)),
+            None => None
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        return self.iter.size_hint();
+    }
+}
+
+impl<'self, K, V> DoubleEndedIterator<(&'self K, &'self K, &'self V)>
+        for FlatIntervalMapIterator<'self, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<(&'self K, &'self K, &'self V)> {
+        match self.iter.next_back() {
+            Some(&(ref s, ref e, ref v)) => Some((s, e, v)),
+            None => None
+        }
+    }
+}
+
+/// The number of reader counters of an RcuState. Readers are spread over
+/// them by the address of their stack, so readers on different threads
+/// mostly count on different cache lines.
+static RCU_READER_STRIPES: uint = 16;
+
+/// A pair of reader counters on a cache line of its own.
+struct ReaderStripe {
+    // counts[e & 1] counts the readers on this stripe which entered
+    // during epoch e
+    counts: [AtomicUint, ..2],
+    pad: [u8, ..48],
+}
+
+struct RcuState<K, V> {
+    pad0: [u8, ..64],
+    current: AtomicPtr<FlatMap<K, V>>,
+    pad1: [u8, ..64],
+    epoch: AtomicUint,
+    pad2: [u8, ..64],
+    readers: [ReaderStripe, ..RCU_READER_STRIPES],
+    writer: AtomicUint,
+    pad3: [u8, ..64],
+}
+
+/// Leaves the read side of an RcuState when dropped, even if the reader
+/// fails.
+struct RcuReadGuard {
+    readers: *mut AtomicUint,
+}
+
+impl Drop for RcuReadGuard {
+    fn drop(&mut self) {
+        unsafe {
+            (*self.readers).fetch_sub(1, Release);
+        }
+    }
+}
+
+/// Releases the writer lock of an RcuState when dropped, even if the
+/// writer fails.
+struct RcuWriteGuard {
+    writer: *mut AtomicUint,
+}
+
+impl Drop for RcuWriteGuard {
+    fn drop(&mut self) {
+        unsafe {
+            (*self.writer).store(0, Release);
+        }
+    }
+}
+
+/// Pick the reader stripe of the calling thread. Threads run on separate
+/// stacks, so the page of a local variable tells them apart.
+#[inline]
+fn reader_stripe() -> uint {
+    let local = 0u;
+    let page = ptr::to_unsafe_ptr(&local) as uint >> 12;
+    (page * 0x9e3779b9 >> 16) % RCU_READER_STRIPES
+}
+
+impl<K: Send, V: Send> RcuState<K, V> {
+    fn new(map: FlatMap<K, V>) -> RcuState<K, V> {
+        unsafe {
+            RcuState{
+                pad0: [0, ..64],
+                current: AtomicPtr::new(cast::transmute(~map)),
+                pad1: [0, ..64],
+                epoch: AtomicUint::new(0),
+                pad2: [0, ..64],
+                // zeroed counters are counters at zero
+                readers: intrinsics::init(),
+                writer: AtomicUint::new(0),
+                pad3: [0, ..64],
+            }
+        }
+    }
+
+    fn read<U>(&mut self, f: &fn(&FlatMap<K, V>) -> U) -> U {
+        let stripe = reader_stripe();
+        let mut epoch;
+        loop {
+            epoch = self.epoch.load(SeqCst);
+            self.readers[stripe].counts[epoch & 1].fetch_add(1, SeqCst);
+            if self.epoch.load(SeqCst) == epoch {
+                break;
+            }
+            // a writer flipped the epoch in between and may already be
+            // waiting on this counter, so enter again
+            self.readers[stripe].counts[epoch & 1].fetch_sub(1, SeqCst);
+        }
+        let readers = &mut self.readers[stripe].counts[epoch & 1] as *mut AtomicUint;
+        let _guard = RcuReadGuard{readers: readers};
+        unsafe { f(&*self.current.load(SeqCst)) }
+    }
+
+    /// Take the writer lock, which is released when the returned guard is
+    /// dropped.
+    fn lock(&mut self) -> RcuWriteGuard {
+        while self.writer.compare_and_swap(0, 1, Acquire) != 0 {
+            task::deschedule();
+        }
+        RcuWriteGuard{writer: &mut self.writer as *mut AtomicUint}
+    }
+
+    /// Publish `map` and free the map it replaces once no reader can still
+    /// be using it. The writer lock must be held.
+    fn publish_locked(&mut self, map: FlatMap<K, V>) {
+        unsafe {
+            let old = self.current.swap(cast::transmute(~map), SeqCst);
+            // readers which enter from now on see the new map, so only
+            // those counted under the old epoch can hold the old one
+            let epoch = self.epoch.load(SeqCst);
+            self.epoch.store(epoch + 1, SeqCst);
+            for stripe in self.readers.iter() {
+                while stripe.counts[epoch & 1].load(SeqCst) != 0 {
+                    task::deschedule();
+                }
+            }
+            let _: ~FlatMap<K, V> = cast::transmute(old);
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<K: Send, V: Send> Drop for RcuState<K, V> {
+    fn drop(&mut self) {
+        unsafe {
+            let _: ~FlatMap<K, V> = cast::transmute(self.current.load(Relaxed));
+        }
+    }
+}
+
+/// A FlatMap shared between tasks which is read far more often than it is
+/// written. Readers use the current map without locking. A writer builds
+/// a new map and publishes it with an atomic swap, then frees the old map
+/// once the readers which might be using it have finished. Writers are
+/// serialized. Clones share the same map.
+///
+/// Each read still makes two atomic changes to a shared counter. Readers
+/// are spread over 16 counters by thread, so they scale until several
+/// busy threads land on the same counter, and a writer checks all 16.
+pub struct ConcurrentFlatMap<K, V> {
+    priv state: UnsafeArc<RcuState<K, V>>,
+}
+
+impl<K: Send, V: Send> Clone for ConcurrentFlatMap<K, V> {
+    fn clone(&self) -> ConcurrentFlatMap<K, V> {
+        ConcurrentFlatMap{state: self.state.clone()}
+    }
+}
+
+impl<K: Send + TotalOrd, V: Send> ConcurrentFlatMap<K, V> {
+    /// Creates an empty ConcurrentFlatMap.
+    pub fn new() -> ConcurrentFlatMap<K, V> {
+        ConcurrentFlatMap::from_flatmap(FlatMap::new())
+    }
+}
+
+impl<K: Send, V: Send> ConcurrentFlatMap<K, V> {
+    /// Create a ConcurrentFlatMap which starts with `map`.
+    pub fn from_flatmap(map: FlatMap<K, V>) -> ConcurrentFlatMap<K, V> {
+        ConcurrentFlatMap{state: UnsafeArc::new(RcuState::new(map))}
+    }
+
+    /// Call `f` with the current map. The map is not freed while `f` runs,
+    /// but a writer publishing a new map waits for `f` to return, so `f`
+    /// should be short. For the same reason `f` must not call `publish` or
+    /// `update` on this map or a clone of it, which would wait forever.
+    pub fn read<U>(&self, f: &fn(&FlatMap<K, V>) -> U) -> U {
+        unsafe { (*self.state.get()).read(f) }
+    }
+
+    /// Replace the current map with `map`, returning once the old map has
+    /// been freed. Calling this from within `read` deadlocks.
+    pub fn publish(&self, map: FlatMap<K, V>) {
+        unsafe {
+            let state = self.state.get();
+            let _guard = (*state).lock();
+            (*state).publish_locked(map);
+        }
+    }
+
+    /// Replace the current map with the map `f` builds from it. Writers are
+    /// serialized, so no update is lost. Calling this from within `read`
+    /// deadlocks, as does writing to the map from within `f`.
+    pub fn update(&self, f: &fn(&FlatMap<K, V>) -> FlatMap<K, V>) {
+        unsafe {
+            let state = self.state.get();
+            let _guard = (*state).lock();
+            let map = f(&*(*state).current.load(SeqCst));
+            (*state).publish_locked(map);
+        }
+    }
+}
+
+impl<K: Send + TotalOrd, V: Send + Clone> ConcurrentFlatMap<K, V> {
+    /// Return a copy of the value for `key` in the current map.
+    pub fn find_copy(&self, key: &K) -> Option<V> {
+        do self.read |map| {
+            match map.find(key) {
+                Some(v) => Some(v.clone()),
+                None => None,
+            }
+        }
+    }
+}
+
+/// A type which can be copied to and from disk byte for byte.
+///
+/// Implementing this trait is unsafe, though the compiler does not say so:
+/// the type must not contain pointers or anything else which only makes
+/// sense within one process, and every bit pattern of its size must be a
+/// valid value, since a file can hold any bytes. `bool` and `char` break
+/// the second rule. Types which need dropping are refused at run time by
+/// `write_to` and `FlatMapView::open`.
+pub trait PlainData: Clone {}
+
+macro_rules! plain_data_impl {
+    ($($t:ty),+) => ($(
+        impl PlainData for $t {}
+    )+)
+}
+
+plain_data_impl!{u8, u16, u32, u64, uint, i8, i16, i32, i64, int, f32, f64}
+
+/// The file magic of the FlatMap on-disk format.
+static FORMAT_MAGIC: [u8, ..8] = [0x46, 0x4c, 0x41, 0x54, 0x4d, 0x41, 0x50, 0x00];
+
+/// The version of the FlatMap on-disk format. Files are written in native
+/// byte order, so a file from a machine of the other endianness reads as
+/// an unknown version.
+static FORMAT_VERSION: u32 = 1;
+
+/// The header of the FlatMap on-disk format. It is followed by the keys in
+/// order, then by the values, each section aligned for its type.
+struct FormatHeader {
+    magic: [u8, ..8],
+    version: u32,
+    key_size: u32,
+    value_size: u32,
+    reserved: u32,
+    len: u64,
+}
+
+#[inline]
+fn checked_add(a: uint, b: uint) -> Option<uint> {
+    if a > uint::max_value - b { None } else { Some(a + b) }
+}
+
+#[inline]
+fn checked_mul(a: uint, b: uint) -> Option<uint> {
+    if b != 0 && a > uint::max_value / b { None } else { Some(a * b) }
+}
+
+#[inline]
+fn align_up(offset: uint, align: uint) -> Option<uint> {
+    match checked_add(offset, align - 1) {
+        Some(n) => Some(n / align * align),
+        None => None,
+    }
+}
+
+/// The byte offsets of the key and value sections and the total file size
+/// for `len` pairs, or None if the file would not fit in the address space.
+fn format_layout<K, V>(len: uint) -> Option<(uint, uint, uint)> {
+    let key_bytes = match checked_mul(len, sys::size_of::<K>()) {
+        Some(n) => n,
+        None => return None,
+    };
+    let value_bytes = match checked_mul(len, sys::size_of::<V>()) {
+        Some(n) => n,
+        None => return None,
+    };
+    let keys = match align_up(sys::size_of::<FormatHeader>(), sys::min_align_of::<K>()) {
+        Some(n) => n,
+        None => return None,
+    };
+    let values = match checked_add(keys, key_bytes) {
+        Some(n) => match align_up(n, sys::min_align_of::<V>()) {
+            Some(n) => n,
+            None => return None,
+        },
+        None => return None,
+    };
+    match checked_add(values, value_bytes) {
+        Some(end) => Some((keys, values, end)),
+        None => None,
+    }
+}
+
+/// Refuse key and value types which need dropping: whatever their
+/// PlainData impl claims, they cannot be copied byte for byte.
+fn check_plain_data<K, V>() -> Result<(), ~str> {
+    if unsafe { intrinsics::needs_drop::<K>() || intrinsics::needs_drop::<V>() } {
+        Err(~"key and value types must not need dropping")
+    } else {
+        Ok(())
+    }
+}
+
+fn write_raw<T>(w: @Writer, x: &T) {
+    unsafe {
+        do vec::raw::buf_as_slice(ptr::to_unsafe_ptr(x) as *u8, sys::size_of::<T>()) |bytes| {
+            w.write(bytes);
+        }
+    }
+}
+
+impl<K: PlainData, V: PlainData> FlatMap<K, V> {
+    /// Write the map in the on-disk format which `FlatMapView` reads. The
+    /// view searches keys in their natural order, so only maps in that
+    /// order can be written.
+    ///
+    /// # Failure
+    ///
+    /// Fails if the key or value type needs dropping.
+    pub fn write_to(&self, w: @Writer) {
+        match check_plain_data::<K, V>() {
+            Ok(()) => (),
+            Err(e) => fail2!("FlatMap::write_to: {}", e),
+        }
+        let len = self.map.data.len();
+        let (keys, values, _) = format_layout::<K, V>(len)
+            .expect("FlatMap::write_to: map is too large for the on-disk format");
+        let header = FormatHeader{
+            magic: FORMAT_MAGIC,
+            version: FORMAT_VERSION,
+            key_size: sys::size_of::<K>() as u32,
+            value_size: sys::size_of::<V>() as u32,
+            reserved: 0,
+            len: len as u64,
+        };
+        write_raw(w, &header);
+        w.write(vec::from_elem(keys - sys::size_of::<FormatHeader>(), 0u8));
+        for &(ref k, _) in self.map.data.iter() {
+            write_raw(w, k);
+        }
+        w.write(vec::from_elem(values - keys - len * sys::size_of::<K>(), 0u8));
+        for &(_, ref v) in self.map.data.iter() {
+            write_raw(w, v);
+        }
+    }
+
+    /// Write the map to the file at `path` in the on-disk format.
+    pub fn save(&self, path: &Path) -> Result<(), ~str> {
+        match io::file_writer(path, [io::Create, io::Truncate]) {
+            Ok(w) => {
+                self.write_to(w);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A read-only map over a file in the on-disk format, which is memory
+/// mapped and searched in place rather than loaded. The keys in the file
+/// are trusted to be in order.
+///
+/// The file is mapped through its descriptor, so the view is only built
+/// on Unix.
+#[cfg(unix)]
+pub struct FlatMapView<K, V> {
+    priv map: ~MemoryMap,
+    priv len: uint,
+    priv keys: *K,
+    priv values: *V,
+}
+
+#[cfg(unix)]
+impl<K: PlainData + TotalOrd, V: PlainData> FlatMapView<K, V> {
+    /// Map the file at `path` into memory, checking that its header
+    /// matches the key and value types. Key and value types which need
+    /// dropping are refused.
+    pub fn open(path: &Path) -> Result<FlatMapView<K, V>, ~str> {
+        match check_plain_data::<K, V>() {
+            Ok(()) => (),
+            Err(e) => return Err(format!("FlatMapView: {}", e)),
+        }
+        let fd = do path.to_str().with_c_str |p| {
+            unsafe { libc::open(p, libc::O_RDONLY, 0) }
+        };
+        if fd < 0 {
+            return Err(format!("FlatMapView: cannot open {}: {}",
+                               path.to_str(), os::last_os_error()));
+        }
+        let size = unsafe { libc::lseek(fd, 0, libc::SEEK_END) };
+        let map = if size < 0 {
+            Err(os::last_os_error())
+        } else if (size as uint) < sys::size_of::<FormatHeader>() {
+            Err(~"file is too short")
+        } else {
+            match MemoryMap::new(size as uint, ~[MapReadable, MapFd(fd)]) {
+                Ok(map) => Ok(map),
+                Err(e) => Err(e.to_str()),
+            }
+        };
+        // the mapping stays valid once the file is closed
+        unsafe {
+            libc::close(fd);
+        }
+        match map {
+            Ok(map) => FlatMapView::from_map(map, size as uint),
+            Err(e) => Err(format!("FlatMapView: cannot map {}: {}", path.to_str(), e)),
+        }
+    }
+
+    fn from_map(map: ~MemoryMap, size: uint) -> Result<FlatMapView<K, V>, ~str> {
+        let header: &FormatHeader = unsafe { cast::transmute(map.data) };
+        if header.magic.as_slice() != FORMAT_MAGIC.as_slice() {
+            return Err(~"FlatMapView: not a FlatMap file");
+        }
+        if header.version != FORMAT_VERSION {
+            return Err(format!("FlatMapView: unknown format version {}", header.version));
+        }
+        if header.key_size as uint != sys::size_of::<K>() ||
+           header.value_size as uint != sys::size_of::<V>() {
+            return Err(~"FlatMapView: key or value size does not match the file");
+        }
+        if header.len > uint::max_value as u64 {
+            return Err(~"FlatMapView: length in the header is out of range");
+        }
+        let len = header.len as uint;
+        let (keys, values, end) = match format_layout::<K, V>(len) {
+            Some(layout) => layout,
+            None => return Err(~"FlatMapView: length in the header is out of range"),
+        };
+        if end > size {
+            return Err(~"FlatMapView: file is truncated");
+        }
+        let data = map.data as *u8;
+        unsafe {
+            Ok(FlatMapView{map: map, len: len,
+                           keys: ptr::offset(data, keys as int) as *K,
+                           values: ptr::offset(data, values as int) as *V})
+        }
+    }
+
+    /// Return the keys of the map, in order.
+    pub fn keys<'a>(&'a self) -> &'a [K] {
+        unsafe { cast::transmute(raw::Slice{data: self.keys, len: self.len}) }
+    }
+
+    /// Return the values of the map, in the order of their keys.
+    pub fn values<'a>(&'a self) -> &'a [V] {
+        unsafe { cast::transmute(raw::Slice{data: self.values, len: self.len}) }
+    }
+
+    /// Copy the map out of the file into a FlatMap.
+    pub fn to_flatmap(&self) -> FlatMap<K, V> {
+        let data: ~[(K, V)] = self.keys().iter().zip(self.values().iter())
+                                  .map(|(k, v)| (k.clone(), v.clone())).collect();
+        FlatMap::from_data(data)
+    }
+
+    /// An iterator visiting all key-value pairs in order.
+    /// Iterator element type is (&'a K, &'a V).
+    pub fn iter<'a>(&'a self) -> FlatMapViewIterator<'a, K, V> {
+        FlatMapViewIterator{keys: self.keys().iter(), values: self.values().iter()}
+    }
+
+    /// An iterator visiting the key-value pairs whose key lies between
+    /// `lo` and `hi`, in order.
+    pub fn range<'a>(&'a self, lo: Bound<K>, hi: Bound<K>)
+                     -> FlatMapViewIterator<'a, K, V> {
+        let (start, end) = keys_range_indices(self.keys(), lo, hi);
+        FlatMapViewIterator{keys: self.keys().slice(start, end).iter(),
+                            values: self.values().slice(start, end).iter()}
+    }
+}
+
+#[cfg(unix)]
+impl<K, V> Container for FlatMapView<K, V> {
+    /// Return the number of elements in the map.
+    fn len(&self) -> uint {
+        self.len
+    }
+}
+
+#[cfg(unix)]
+impl<K: PlainData + TotalOrd, V: PlainData> Map<K, V> for FlatMapView<K, V> {
+    fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        match self.keys().bsearch(|k| k.cmp(key)) {
+            None => None,
+            Some(idx) => Some(&self.values()[idx]),
+        }
+    }
+}
+
+/// FlatMapView iterator
+#[cfg(unix)]
+pub struct FlatMapViewIterator<'self, K, V> {
+    priv keys: vec::VecIterator<'self, K>,
+    priv values: vec::VecIterator<'self, V>,
+}
+
+#[cfg(unix)]
+impl<'self, K, V> Iterator<(&'self K, &'self V)> for FlatMapViewIterator<'self, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<(&'self K, &'self V)> {
+        match (self.keys.next(), self.values.next()) {
+            (Some(k), Some(v)) => Some((k, v)),
+            _ => None
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        return self.keys.size_hint();
+    }
+}
+
+#[cfg(unix)]
+impl<'self, K, V> DoubleEndedIterator<(&'self K, &'self V)> for FlatMapViewIterator<'self, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<(&'self K, &'self V)> {
+        match (self.keys.next_back(), self.values.next_back()) {
+            (Some(k), Some(v)) => Some((k, v)),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FlatMap;
+
+    #[test]
+    fn test_container_trait() {
+        let m: FlatMap<int, int> = FlatMap::with_capacity(10);
+        assert!(10 == m.capacity());
+        assert!(0 == m.len());
+    }
+
+    #[test]
+    fn test_map_trait() {
+        let m: FlatMap<int, int> = FlatMap::new();
+        assert!(m.find(&0) == None);
+        assert!(m.contains_key(&0) == false);
+    }
+
+    #[test]
+    fn test_mutable_map_trait() {
+        let mut m: FlatMap<int, int> = FlatMap::new();
+        assert!(m.contains_key(&0) == false);
+        m.insert(0, 1);
+        assert!(m.contains_key(&0));
+        m.insert(1, 2);
+        assert!(m.len() == 2);
+        assert!(m.contains_key(&1));
+        m.pop(&0);
+        assert!(m.contains_key(&0) == false);
+        assert!(m.contains_key(&1));
+        assert!(m.len() == 1);
+        m.insert(1, 3);
+        match m.find(&1) {
+            Some(v) => assert_eq!(3, *v),
+            None => fail2!("No entry found for key: 1"),
+        }
+
+        for (k, v) in m.iter() {
+            assert_eq!((1, 3), (*k, *v));
+        }
+
+        for (_, v) in m.mut_iter() {
+            *v = 1;
+        }
+
+        for (k, v) in m.iter() {
+            assert_eq!((1, 1), (*k, *v));
+        }
+
+        assert_eq!((1u, Some(1u)), m.iter().size_hint());
+        m.insert(2, 2);
+        m.insert(3, 3);
+        assert_eq!((3u, Some(3u)), m.iter().size_hint());
+    }
+}
+
+#[cfg(test)]
+mod test_map {
+    use std::ascii::StrAsciiExt;
+    use std::uint;
+    use super::{FlatMap, FlatMapBy, KeepFirst, KeepLast, MergeWith, OrderBy};
+    use super::InterpolationSearch;
+    use super::{Included, Excluded, Unbounded};
+    use super::{Occupied, Vacant};
+    use super::{Diff, Added, Removed, Changed};
+    use super::{merge_iter, merge_all_iter};
+    use super::{CapacityOverflow, MemoryUsage};
+
+    #[test]
+    fn test_create_capacity_zero() {
+        let mut m = FlatMap::with_capacity(0);
+        assert!(m.insert(1, 1));
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut m = FlatMap::new();
+        assert!(m.insert(1, 2));
+        assert!(m.insert(2, 4));
+        assert_eq!(*m.get(&1), 2);
+        assert_eq!(*m.get(&2), 4);
+    }
+
+    #[test]
+    fn test_find_mut() {
+        let mut m = FlatMap::new();
+        assert!(m.insert(1, 12));
+        assert!(m.insert(2, 8));
+        assert!(m.insert(5, 14));
+        let new = 100;
+        match m.find_mut(&5) {
+            None => fail2!(), Some(x) => *x = new
+        }
+        assert_eq!(m.find(&5), Some(&new));
+    }
+
+    #[test]
+    fn test_insert_overwrite() {
+        let mut m = FlatMap::new();
+        assert!(m.insert(1, 2));
+        assert_eq!(*m.get(&1), 2);
+        assert!(!m.insert(1, 3));
+        assert_eq!(*m.get(&1), 3);
+    }
+
+    #[test]
+    fn test_insert_conflicts() {
+        let mut m = FlatMap::with_capacity(4);
+        assert!(m.insert(1, 2));
+        assert!(m.insert(5, 3));
+        assert!(m.insert(9, 4));
+        assert_eq!(*m.get(&9), 4);
+        assert_eq!(*m.get(&5), 3);
+        assert_eq!(*m.get(&1), 2);
+    }
+
+    #[test]
+    fn test_conflict_remove() {
+        let mut m = FlatMap::with_capacity(4);
+        assert!(m.insert(1, 2));
+        assert!(m.insert(5, 3));
+        assert!(m.insert(9, 4));
+        assert!(m.remove(&1));
+        assert_eq!(*m.get(&9), 4);
+        assert_eq!(*m.get(&5), 3);
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let mut m = FlatMap::with_capacity(4);
+        assert!(m.insert(1, 2));
+        assert!(!m.is_empty());
+        assert!(m.remove(&1));
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn test_pop() {
+        let mut m = FlatMap::new();
+        m.insert(1, 2);
+        assert_eq!(m.pop(&1), Some(2));
+        assert_eq!(m.pop(&1), None);
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut m = FlatMap::new();
+        assert_eq!(m.swap(1, 2), None);
+        assert_eq!(m.swap(1, 3), Some(2));
+        assert_eq!(m.swap(1, 4), Some(3));
+    }
+
+    #[test]
+    fn test_find_or_insert() {
+        let mut m: FlatMap<int, int> = FlatMap::new();
+        assert_eq!(*m.find_or_insert(1, 2), 2);
+        assert_eq!(*m.find_or_insert(1, 3), 2);
+    }
+
+    #[test]
+    fn test_find_or_insert_with() {
+        let mut m: FlatMap<int, int> = FlatMap::new();
+        assert_eq!(*m.find_or_insert_with(1, |_| 2), 2);
+        assert_eq!(*m.find_or_insert_with(1, |_| 3), 2);
+    }
+
+    #[test]
+    fn test_insert_or_update_with() {
+        let mut m: FlatMap<int, int> = FlatMap::new();
+        assert_eq!(*m.insert_or_update_with(1, 2, |_,x| *x+=1), 2);
+        assert_eq!(*m.insert_or_update_with(1, 2, |_,x| *x+=1), 3);
+    }
+
+    #[test]
+    fn test_entry_or_insert() {
+        let mut m: FlatMap<int, int> = FlatMap::new();
+        assert_eq!(*m.entry(3).or_insert(30), 30);
+        assert_eq!(*m.entry(3).or_insert(31), 30);
+        assert_eq!(*m.entry(1).or_insert_with(|k| *k * 10), 10);
+        assert_eq!(*m.entry(1).or_insert_with(|_| fail2!()), 10);
+        *m.entry(2).or_insert(0) += 20;
+        let v = m.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, int)]>();
+        assert_eq!(v, ~[(1, 10), (2, 20), (3, 30)]);
+    }
+
+    #[test]
+    fn test_entry_and_modify() {
+        let mut m: FlatMap<int, int> = FlatMap::new();
+        assert_eq!(*m.entry(1).and_modify(|_, v| *v += 1).or_insert(5), 5);
+        assert_eq!(*m.entry(1).and_modify(|_, v| *v += 1).or_insert(5), 6);
+    }
+
+    #[test]
+    fn test_entry_occupied() {
+        let mut m: FlatMap<int, int> = FlatMap::new();
+        m.insert(1, 10);
+        m.insert(2, 20);
+        match m.entry(2) {
+            Occupied(e) => {
+                let mut e = e;
+                assert_eq!(*e.key(), 2);
+                assert_eq!(*e.get(), 20);
+                assert_eq!(e.insert(21), 20);
+                *e.get_mut() += 1;
+                assert_eq!(*e.get(), 22);
+            }
+            Vacant(_) => fail2!(),
+        }
+        match m.entry(1) {
+            Occupied(e) => assert_eq!(e.remove(), 10),
+            Vacant(_) => fail2!(),
+        }
+        match m.entry(1) {
+            Occupied(_) => fail2!(),
+            Vacant(e) => {
+                assert_eq!(*e.key(), 1);
+                *e.insert(11) += 1;
+            }
+        }
+        assert_eq!(m.find(&1), Some(&12));
+        assert_eq!(m.find(&2), Some(&22));
+    }
+
+    #[test]
+    fn test_move_iter() {
+        let hm = {
+            let mut hm = FlatMap::new();
+
+            hm.insert('a', 1);
+            hm.insert('b', 2);
+
+            hm
+        };
+
+        let v = hm.move_iter().collect::<~[(char, int)]>();
+        assert!([('a', 1), ('b', 2)] == v || [('b', 2), ('a', 1)] == v);
+    }
+
+    #[test]
+    fn test_iterate() {
+        let mut m = FlatMap::with_capacity(4);
+        for i in range(0u, 32) {
+            assert!(m.insert(i, i*2));
+        }
+        let mut observed = 0;
+        for (k, v) in m.iter() {
+            assert_eq!(*v, *k * 2);
+            observed |= (1 << *k);
+        }
+        assert_eq!(observed, 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn test_find() {
+        let mut m = FlatMap::new();
+        assert!(m.find(&1).is_none());
+        m.insert(1, 2);
+        match m.find(&1) {
+            None => fail2!(),
+            Some(v) => assert!(*v == 2)
+        }
+    }
+
+    #[test]
+    fn test_eq() {
+        let mut m1 = FlatMap::new();
+        m1.insert(1, 2);
+        m1.insert(2, 3);
+        m1.insert(3, 4);
+
+        let mut m2 = FlatMap::new();
+        m2.insert(1, 2);
+        m2.insert(2, 3);
+
+        assert!(m1 != m2);
+
+        m2.insert(3, 4);
+
+        assert_eq!(m1, m2);
+    }
+
+    #[test]
+    fn test_find_equiv() {
+        let mut m = FlatMap::new();
+
+        let (foo, bar, baz) = (1,2,3);
+        m.insert(~"foo", foo);
+        m.insert(~"bar", bar);
+        m.insert(~"baz", baz);
+
+
+        assert_eq!(m.find_equiv(&("foo")), Some(&foo));
+        assert_eq!(m.find_equiv(&("bar")), Some(&bar));
+        assert_eq!(m.find_equiv(&("baz")), Some(&baz));
+
+        assert_eq!(m.find_equiv(&("qux")), None);
+    }
+
+    #[test]
+    fn test_find_equiv_many() {
+        let mut m = FlatMap::new();
+        for i in range(0, 100) {
+            m.insert(format!("key{:03d}", i), i);
+        }
+
+        for i in range(0, 100) {
+            let k = format!("key{:03d}", i);
+            assert_eq!(m.find_equiv(&k.as_slice()), Some(&i));
+        }
+        assert_eq!(m.find_equiv(&("key")), None);
+        assert_eq!(m.find_equiv(&("key0995")), None);
+        assert_eq!(m.find_equiv(&("zzz")), None);
+    }
+
+    #[test]
+    fn test_pop_many() {
+        let mut m = FlatMap::new();
+        for i in range(0, 64) {
+            m.insert(i, i * 2);
+        }
+        for i in range(0, 64).filter(|&i| i % 3 == 0) {
+            assert_eq!(m.pop(&i), Some(i * 2));
+            assert_eq!(m.pop(&i), None);
+        }
+        for i in range(0, 64) {
+            assert_eq!(m.contains_key(&i), i % 3 != 0);
+        }
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let xs = ~[(1, 1), (2, 2), (3, 3), (4, 4), (5, 5), (6, 6)];
+
+        let map: FlatMap<int, int> = xs.iter().map(|&x| x).collect();
+
+        for &(k, v) in xs.iter() {
+            assert_eq!(map.find(&k), Some(&v));
+        }
+    }
+
+    #[test]
+    fn test_from_iter_unsorted() {
+        let xs = ~[(5, 1), (1, 2), (3, 3), (1, 4), (5, 5), (2, 6)];
+
+        let map: FlatMap<int, int> = xs.iter().map(|&x| x).collect();
+
+        assert_eq!(map.len(), 4);
+        let v = map.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, int)]>();
+        assert_eq!(v, ~[(1, 4), (2, 6), (3, 3), (5, 5)]);
+    }
+
+    #[test]
+    fn test_from_vec_duplicates() {
+        let xs = ~[(2, 1), (1, 2), (2, 3), (2, 4)];
+
+        let m = FlatMap::from_vec(xs.clone(), KeepFirst);
+        assert_eq!(m.find(&2), Some(&1));
+        let m = FlatMap::from_vec(xs.clone(), KeepLast);
+        assert_eq!(m.find(&2), Some(&4));
+        let m = FlatMap::from_vec(xs, MergeWith(|_, a, b| a + b));
+        assert_eq!(m.find(&2), Some(&8));
+        assert_eq!(m.find(&1), Some(&2));
+    }
+
+    #[test]
+    fn test_from_vec_large() {
+        let n = 1000;
+        let xs = range(0, n).map(|i| ((i * 7919) % n, i)).collect::<~[(int, int)]>();
+        let m = FlatMap::from_vec(xs, KeepLast);
+        assert_eq!(m.len(), n as uint);
+        for (i, (&k, &v)) in m.iter().enumerate() {
+            assert_eq!(k, i as int);
+            assert_eq!((v * 7919) % n, k);
+        }
+    }
+
+    #[test]
+    fn test_from_sorted_vec() {
+        let m = FlatMap::from_sorted_vec(~[(1, 'a'), (2, 'b'), (4, 'c')]);
+        assert_eq!(m.len(), 3);
+        assert_eq!(m.find(&4), Some(&'c'));
+        assert_eq!(m.find(&3), None);
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_from_sorted_vec_unsorted() {
+        FlatMap::from_sorted_vec(~[(1, 'a'), (4, 'b'), (2, 'c')]);
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_from_sorted_vec_duplicate() {
+        FlatMap::from_sorted_vec(~[(1, 'a'), (1, 'b')]);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let m = FlatMap::from_sorted_vec(~[(1, 'a'), (3, 'b'), (5, 'c'), (7, 'd')]);
+
+        let keys = m.lower_bound(&3).map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[3, 5, 7]);
+        let keys = m.lower_bound(&4).map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[5, 7]);
+        let keys = m.upper_bound(&3).map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[5, 7]);
+        assert!(m.upper_bound(&7).next().is_none());
+        assert_eq!(m.lower_bound(&0).next(), Some((&1, &'a')));
+    }
+
+    #[test]
+    fn test_range() {
+        let m = FlatMap::from_sorted_vec(~[(1, 'a'), (3, 'b'), (5, 'c'), (7, 'd')]);
+
+        let keys = m.range(Included(&3), Included(&7)).map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[3, 5, 7]);
+        let keys = m.range(Excluded(&3), Excluded(&7)).map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[5]);
+        let keys = m.range(Unbounded, Excluded(&5)).map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[1, 3]);
+        let keys = m.range(Included(&2), Unbounded).map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[3, 5, 7]);
+        assert!(m.range(Included(&6), Excluded(&2)).next().is_none());
+        assert!(m.range(Excluded(&3), Excluded(&5)).next().is_none());
+
+        let keys = m.range(Included(&3), Unbounded).invert().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[7, 5, 3]);
+    }
+
+    #[test]
+    fn test_extend_with() {
+        let mut m = FlatMap::from_sorted_vec(~[(1, 10), (3, 30), (5, 50)]);
+        m.extend_with(&mut (~[(4, 41), (3, 31), (0, 1)]).move_iter(), KeepFirst);
+        let v = m.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, int)]>();
+        assert_eq!(v, ~[(0, 1), (1, 10), (3, 30), (4, 41), (5, 50)]);
+
+        m.extend(&mut (~[(5, 51), (6, 61)]).move_iter());
+        assert_eq!(m.find(&5), Some(&51));
+        assert_eq!(m.find(&6), Some(&61));
+        assert_eq!(m.len(), 6);
+    }
+
+    #[test]
+    fn test_merge() {
+        let a = FlatMap::from_sorted_vec(~[(1, 10), (3, 30), (5, 50)]);
+        let b = FlatMap::from_sorted_vec(~[(2, 2), (3, 3), (6, 6)]);
+        let m = a.merge(b, |_k, x, y| x + y);
+        let v = m.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, int)]>();
+        assert_eq!(v, ~[(1, 10), (2, 2), (3, 33), (5, 50), (6, 6)]);
+
+        let m = m.merge(FlatMap::new(), |_k, x, _y| x);
+        assert_eq!(m.len(), 5);
+    }
+
+    #[test]
+    fn test_append() {
+        let mut a = FlatMap::from_sorted_vec(~[(1, 'a'), (4, 'b')]);
+        a.append(FlatMap::from_sorted_vec(~[(0, 'x'), (4, 'y'), (9, 'z')]));
+        let v = a.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, char)]>();
+        assert_eq!(v, ~[(0, 'x'), (1, 'a'), (4, 'y'), (9, 'z')]);
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = FlatMap::from_sorted_vec(~[(1, 'a'), (2, 'b'), (4, 'd'), (5, 'e')]);
+        let new = FlatMap::from_sorted_vec(~[(0, 'z'), (2, 'b'), (4, 'x'), (6, 'f')]);
+        let changes = old.diff(&new).collect::<~[Diff<int, char>]>();
+        assert_eq!(changes, ~[Added(&0, &'z'), Removed(&1, &'a'), Changed(&4, &'d', &'x'),
+                              Removed(&5, &'e'), Added(&6, &'f')]);
+
+        assert!(old.diff(&old).next().is_none());
+        let empty = FlatMap::new();
+        assert!(empty.diff(&new).all(|d| match d { Added(_, _) => true, _ => false }));
+        assert!(old.diff(&empty).all(|d| match d { Removed(_, _) => true, _ => false }));
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut m: FlatMap<int, int> = range(0, 10).map(|i| (i, i * i)).collect();
+        m.retain(|&k, &v| k % 2 == 0 && v < 40);
+        let keys = m.iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn test_drain_range() {
+        let mut m = FlatMap::from_sorted_vec(~[(0, 'a'), (1, 'b'), (2, 'c'),
+                                               (3, 'd'), (4, 'e'), (5, 'f')]);
+        let drained = m.drain_range(Excluded(&1), Included(&3));
+        assert_eq!(drained, ~[(2, 'c'), (3, 'd')]);
+        let keys = m.iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[0, 1, 4, 5]);
+        assert_eq!(m.drain_range(Included(&7), Unbounded), ~[]);
+        assert_eq!(m.drain_range(Unbounded, Unbounded).len(), 4);
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn test_split_off() {
+        let mut m: FlatMap<int, int> = range(0, 6).map(|i| (i * 2, i)).collect();
+        let tail = m.split_off(&5);
+        let keys = m.iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[0, 2, 4]);
+        let keys = tail.iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[6, 8, 10]);
+        assert!(m.split_off(&100).is_empty());
+        assert_eq!(m.split_off(&0).len(), 3);
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut m: FlatMap<int, int> = range(0, 10).map(|i| (i, i)).collect();
+        m.truncate_front(7);
+        m.truncate_back(4);
+        let keys = m.iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[3, 4, 5, 6]);
+        m.truncate_front(10);
+        m.truncate_back(10);
+        assert_eq!(m.len(), 4);
+        m.truncate_front(0);
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn test_dedup_by() {
+        let mut m: FlatMap<int, int> = range(0, 25).map(|i| (i, 1)).collect();
+        m.dedup_by(|&a, &b| a / 10 == b / 10, MergeWith(|_k, a, b| a + b));
+        let v = m.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, int)]>();
+        assert_eq!(v, ~[(0, 10), (10, 10), (20, 5)]);
+    }
+
+    #[test]
+    fn test_rev_iterators() {
+        let mut m: FlatMap<int, int> = range(0, 5).map(|i| (i, i)).collect();
+        let keys = m.rev_iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[4, 3, 2, 1, 0]);
+
+        {
+            let mut it = m.mut_iter();
+            let (_, v) = it.next_back().unwrap();
+            *v = 40;
+            let (_, v) = it.next().unwrap();
+            *v = -1;
+        }
+        for (k, v) in m.mut_rev_iter().take(2) { *v += *k; }
+        let v = m.iter().map(|(_, &v)| v).collect::<~[int]>();
+        assert_eq!(v, ~[-1, 1, 2, 6, 44]);
+
+        let mut it = m.clone().move_iter();
+        assert_eq!(it.size_hint(), (5, Some(5)));
+        assert_eq!(it.next(), Some((4, 44)));
+        assert_eq!(it.next_back(), Some((0, -1)));
+        assert_eq!(it.next(), Some((3, 6)));
+        assert_eq!(it.size_hint(), (2, Some(2)));
+        assert_eq!(it.next_back(), Some((1, 1)));
+        assert_eq!(it.next_back(), Some((2, 2)));
+        assert_eq!(it.next(), None);
+        assert_eq!(it.next_back(), None);
+
+        let keys = m.clone().move_iter().map(|(k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[4, 3, 2, 1, 0]);
+        let keys = m.move_iter().invert().map(|(k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_move_iter_partial() {
+        // the pairs left in the iterator are dropped with it
+        let m: FlatMap<int, ~str> = range(0, 10).map(|i| (i, i.to_str())).collect();
+        let mut it = m.move_iter();
+        assert_eq!(it.next(), Some((9, ~"9")));
+        assert_eq!(it.next_back(), Some((0, ~"0")));
+    }
+
+    #[test]
+    fn test_cursor() {
+        let m: FlatMap<int, int> = range(0, 10).map(|i| (i * 10, i)).collect();
+        let mut c = m.cursor();
+        assert_eq!(c.get(), Some((&0, &0)));
+        assert!(!c.move_prev());
+        assert!(c.seek(&30));
+        assert_eq!(c.index(), 3);
+        assert!(!c.seek(&75));
+        assert_eq!(c.get(), Some((&80, &8)));
+        assert!(c.move_prev());
+        assert_eq!(c.get(), Some((&70, &7)));
+        assert!(c.seek(&10));
+        assert_eq!(c.index(), 1);
+        assert!(!c.seek(&1000));
+        assert!(c.get().is_none());
+        assert!(!c.move_next());
+        assert!(c.move_prev());
+        assert_eq!(c.get(), Some((&90, &9)));
+    }
+
+    #[test]
+    fn test_cursor_merge_join() {
+        let a: FlatMap<int, int> = range(0, 100).map(|i| (i * 3, i)).collect();
+        let b: FlatMap<int, int> = range(0, 100).map(|i| (i * 5, i)).collect();
+        let mut c = a.cursor();
+        let mut joined = ~[];
+        for (k, _) in b.iter() {
+            if c.seek(k) {
+                joined.push(*k);
+            }
+        }
+        assert_eq!(joined, range(0, 20).map(|i| i * 15).collect::<~[int]>());
+    }
+
+    #[test]
+    fn test_cursor_mut() {
+        let mut m = FlatMap::from_sorted_vec(~[(1, 'a'), (3, 'c'), (5, 'e')]);
+        {
+            let mut c = m.cursor_mut();
+            assert!(!c.seek(&2));
+            c.insert(2, 'b');
+            assert_eq!(c.get(), Some((&2, &'b')));
+            assert!(c.move_next());
+            match c.get_mut() {
+                Some((_, v)) => *v = 'C',
+                None => fail2!("missing pair"),
+            }
+            assert!(c.move_next());
+            assert_eq!(c.remove(), Some((5, 'e')));
+            assert!(c.get().is_none());
+            assert_eq!(c.remove(), None);
+            c.insert(9, 'i');
+        }
+        let v = m.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, char)]>();
+        assert_eq!(v, ~[(1, 'a'), (2, 'b'), (3, 'C'), (9, 'i')]);
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_cursor_mut_insert_out_of_order() {
+        let mut m = FlatMap::from_sorted_vec(~[(1, 'a'), (3, 'c')]);
+        let mut c = m.cursor_mut();
+        c.move_next();
+        c.insert(3, 'x');
+    }
+
+    #[test]
+    fn test_interpolation_search() {
+        let mut uniform = FlatMapBy::with_comparator(InterpolationSearch);
+        uniform.extend(&mut range(0u64, 1000).map(|i| (i * 7, i)));
+        let natural: FlatMap<u64, u64> =
+            range(0u64, 1000).map(|i| (i * 7, i)).collect();
+        for k in range(0u64, 7001) {
+            assert_eq!(uniform.find(&k), natural.find(&k));
+            assert_eq!(uniform.lower_bound(&k).next(), natural.lower_bound(&k).next());
+        }
+
+        let mut skewed = FlatMapBy::with_comparator(InterpolationSearch);
+        skewed.extend(&mut range(0u64, 1000).map(|i| (i * i * i, i)));
+        for i in range(0u64, 1001) {
+            let k = i * i * i;
+            assert_eq!(skewed.contains_key(&k), i < 1000);
+            assert!(!skewed.contains_key(&(k + 1)));
+        }
+        // entries, removal and insertion search the same way
+        assert_eq!(skewed.pop(&27), Some(3));
+        assert!(!skewed.contains_key(&27));
+        assert!(skewed.insert(27, 3));
+        *skewed.entry(8).or_insert(0) += 10;
+        assert_eq!(skewed.find(&8), Some(&12));
+        assert_eq!(skewed.len(), 1000);
+
+        let mut signed = FlatMapBy::with_comparator(InterpolationSearch);
+        signed.extend(&mut range(-50, 50).map(|i| (i * 3, i)));
+        for k in range(-200, 200) {
+            if k % 3 == 0 && k >= -150 && k < 150 {
+                assert_eq!(*signed.get(&k), k / 3);
+            } else {
+                assert!(!signed.contains_key(&k));
+            }
+        }
+
+        let empty: FlatMapBy<u8, (), InterpolationSearch> =
+            FlatMapBy::with_comparator(InterpolationSearch);
+        assert!(empty.find(&0).is_none());
+    }
+
+    #[test]
+    fn test_prefix_iter() {
+        let m: FlatMap<~str, int> =
+            [~"a/", ~"a/b", ~"a/b/c", ~"a/c", ~"a0", ~"b/x", ~"ab"]
+            .iter().enumerate().map(|(i, k)| (k.clone(), i as int)).collect();
+        let keys = m.prefix_iter("a/").map(|(k, _)| k.clone()).collect::<~[~str]>();
+        assert_eq!(keys, ~[~"a/", ~"a/b", ~"a/b/c", ~"a/c"]);
+        let keys = m.prefix_iter("a/b").invert().map(|(k, _)| k.clone()).collect::<~[~str]>();
+        assert_eq!(keys, ~[~"a/b/c", ~"a/b"]);
+        assert_eq!(m.prefix_iter("").size_hint(), (7, Some(7)));
+        assert!(m.prefix_iter("c").next().is_none());
+        assert!(m.prefix_iter("a/bc").next().is_none());
+    }
+
+    #[test]
+    fn test_longest_prefix_match() {
+        let m: FlatMap<~str, int> =
+            [~"/", ~"/usr/", ~"/usr/local/", ~"/var/lib/", ~"/usr/locale"]
+            .iter().enumerate().map(|(i, k)| (k.clone(), i as int)).collect();
+        assert_eq!(m.longest_prefix_match("/usr/local/bin"), Some((&~"/usr/local/", &2)));
+        assert_eq!(m.longest_prefix_match("/usr/lib"), Some((&~"/usr/", &1)));
+        assert_eq!(m.longest_prefix_match("/var/log"), Some((&~"/", &0)));
+        assert_eq!(m.longest_prefix_match("/usr/locale"), Some((&~"/usr/locale", &4)));
+        assert_eq!(m.longest_prefix_match("relative"), None);
+        assert_eq!(m.longest_prefix_match("/é/"), Some((&~"/", &0)));
+
+        let empty: FlatMap<~str, int> = FlatMap::new();
+        assert_eq!(empty.longest_prefix_match("/"), None);
+    }
+
+    #[test]
+    fn test_merge_iter() {
+        let a = FlatMap::from_sorted_vec(~[(1, 10), (4, 40), (7, 70)]);
+        let b = FlatMap::from_sorted_vec(~[(2, 2), (4, 4)]);
+        let c = FlatMap::from_sorted_vec(~[(0, 0), (4, 400), (7, 700), (9, 900)]);
+        let empty = FlatMap::new();
+        let maps = &[&a, &empty, &b, &c];
+
+        let all = merge_all_iter(maps).map(|(&k, &v)| (k, v)).collect::<~[(int, int)]>();
+        assert_eq!(all, ~[(0, 0), (1, 10), (2, 2), (4, 40), (4, 4), (4, 400),
+                          (7, 70), (7, 700), (9, 900)]);
+        assert_eq!(merge_all_iter(maps).size_hint(), (9, Some(9)));
+
+        let first = merge_iter(maps, KeepFirst).collect::<~[(&int, int)]>();
+        assert_eq!(first, ~[(&0, 0), (&1, 10), (&2, 2), (&4, 40), (&7, 70), (&9, 900)]);
+        let last = merge_iter(maps, KeepLast).map(|(_, v)| v).collect::<~[int]>();
+        assert_eq!(last, ~[0, 10, 2, 400, 700, 900]);
+        let sum = merge_iter(maps, MergeWith(|_k, x, y| x + y))
+                      .map(|(_, v)| v).collect::<~[int]>();
+        assert_eq!(sum, ~[0, 10, 2, 444, 770, 900]);
+
+        assert!(merge_iter::<int, int>(&[], KeepLast).next().is_none());
+    }
+
+    #[test]
+    fn test_try_reserve() {
+        let mut m: FlatMap<int, int> = FlatMap::new();
+        assert_eq!(m.try_reserve(100), Ok(()));
+        assert!(m.capacity() >= 100);
+        assert_eq!(m.try_reserve(uint::max_value), Err(CapacityOverflow));
+        // fits in a uint, but not once multiplied by the size of a pair
+        assert_eq!(m.try_reserve(uint::max_value / 2), Err(CapacityOverflow));
+        assert!(m.is_empty());
+        assert!(m.try_insert(1, 1).is_ok());
+    }
+
+    #[test]
+    fn test_try_insert() {
+        let mut m = FlatMap::new();
+        assert_eq!(m.try_insert(2, 20), Ok(true));
+        assert_eq!(m.try_insert(1, 10), Ok(true));
+        assert_eq!(m.try_insert(2, 200), Ok(false));
+        assert_eq!(m.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, int)]>(),
+                   ~[(1, 10), (2, 200)]);
+    }
+
+    #[test]
+    fn test_try_from_iter() {
+        let pairs = ~[(3, 'c'), (1, 'a'), (3, 'z'), (2, 'b')];
+        let m = FlatMap::try_from_iter(&mut pairs.move_iter(), KeepLast).unwrap();
+        assert_eq!(m.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, char)]>(),
+                   ~[(1, 'a'), (2, 'b'), (3, 'z')]);
+
+        // long enough to merge runs, in an odd and an even number of passes
+        for &n in [300, 1000].iter() {
+            let mut pairs = range(0, n).map(|i| ((i * 37) % 100, i));
+            let m = FlatMap::try_from_iter(&mut pairs, KeepFirst).unwrap();
+            assert_eq!(m.len(), 100);
+            assert!(m.iter().all(|(&k, &v)| (v * 37) % 100 == k && v < 100));
+        }
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let mut m = FlatMap::with_capacity(16);
+        m.insert(1u64, 1u64);
+        m.insert(2u64, 2u64);
+        let usage = m.memory_usage();
+        assert_eq!(usage.used, 32);
+        assert!(usage.allocated >= 16 * 16);
+
+        m.shrink_to_fit();
+        assert_eq!(m.capacity(), 2);
+        assert_eq!(m.memory_usage(), MemoryUsage{used: 32, allocated: 32});
+    }
+
+    #[test]
+    fn test_total_ord() {
+        let a = FlatMap::from_sorted_vec(~[(1, 1), (2, 2)]);
+        let b = FlatMap::from_sorted_vec(~[(1, 1), (2, 3)]);
+        let c = FlatMap::from_sorted_vec(~[(1, 1)]);
+        assert!(a.equals(&a.clone()));
+        assert!(!a.equals(&b));
+        assert_eq!(a.cmp(&b), Less);
+        assert_eq!(b.cmp(&a), Greater);
+        assert_eq!(c.cmp(&a), Less);
+        assert_eq!(a.cmp(&a.clone()), Equal);
+    }
+
+    #[test]
+    fn test_hash() {
+        let a = FlatMap::from_sorted_vec(~[(1, 1), (2, 2)]);
+        let b: FlatMap<int, int> = ~[(2, 2), (1, 1)].move_iter().collect();
+        assert_eq!(a.hash(), b.hash());
+
+        let mut nested = FlatMap::new();
+        nested.insert(a, ~"a");
+        nested.insert(FlatMap::from_sorted_vec(~[(1, 1)]), ~"c");
+        assert_eq!(nested.find(&b), Some(&~"a"));
+        assert_eq!(nested.len(), 2);
+    }
+
+    #[test]
+    fn test_show() {
+        let m = FlatMap::from_sorted_vec(~[(1, ~"one"), (2, ~"two")]);
+        assert_eq!(format!("{}", m), ~"{1: one, 2: two}");
+        assert_eq!(m.to_str(), ~"{1: one, 2: two}");
+
+        let empty: FlatMap<int, int> = FlatMap::new();
+        assert_eq!(format!("{}", empty), ~"{}");
+        assert_eq!(empty.to_str(), ~"{}");
+    }
+
+    #[test]
+    fn test_index() {
+        let m = FlatMap::from_sorted_vec(~[(1, ~"one"), (2, ~"two")]);
+        assert_eq!(m[2], ~"two");
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_index_missing() {
+        let m = FlatMap::from_sorted_vec(~[(1, 1)]);
+        m[3];
+    }
+
+    fn reverse(a: &int, b: &int) -> Ordering {
+        b.cmp(a)
+    }
+
+    fn case_insensitive(a: &~str, b: &~str) -> Ordering {
+        a.to_ascii_lower().cmp(&b.to_ascii_lower())
+    }
+
+    #[test]
+    fn test_comparator() {
+        let mut m = FlatMapBy::with_comparator(OrderBy(reverse));
+        for i in range(0, 10) {
+            assert!(m.insert(i, i * 10));
+        }
+        assert_eq!(m.find(&3), Some(&30));
+        assert_eq!(m.find(&10), None);
+        let keys = m.iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[9, 8, 7, 6, 5, 4, 3, 2, 1, 0]);
+
+        let keys = m.range(Included(&7), Excluded(&4)).map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[7, 6, 5]);
+        assert_eq!(m.pop(&7), Some(70));
+        assert_eq!(m.len(), 9);
+    }
+
+    #[test]
+    fn test_comparator_case_insensitive() {
+        let mut m = FlatMapBy::with_comparator(OrderBy(case_insensitive));
+        m.insert(~"Apple", 1);
+        m.insert(~"banana", 2);
+        assert!(!m.insert(~"APPLE", 3));
+        assert_eq!(m.len(), 2);
+        assert_eq!(m.find(&~"apple"), Some(&3));
+
+        let mut other = FlatMapBy::with_comparator(OrderBy(case_insensitive));
+        other.insert(~"BANANA", 2);
+        other.insert(~"apple", 3);
+        assert_eq!(m, other);
+    }
+
+    #[test]
+    fn test_rank_select() {
+        let mut m = FlatMap::from_sorted_vec(~[(10, 'a'), (20, 'b'), (30, 'c')]);
+        assert_eq!(m.rank(&5), 0);
+        assert_eq!(m.rank(&10), 0);
+        assert_eq!(m.rank(&25), 2);
+        assert_eq!(m.rank(&40), 3);
+        assert_eq!(m.select(1), Some((&20, &'b')));
+        assert_eq!(m.select(3), None);
+
+        match m.nth_mut(2) {
+            Some((_, v)) => *v = 'z',
+            None => fail2!("missing pair"),
+        }
+        assert_eq!(m.find(&30), Some(&'z'));
+        assert!(m.nth_mut(3).is_none());
+    }
+
+    #[test]
+    fn test_percentile() {
+        let m: FlatMap<int, ()> = range(1, 101).map(|i| (i, ())).collect();
+        assert_eq!(m.percentile(0.0), Some((&1, &())));
+        assert_eq!(m.percentile(50.0), Some((&50, &())));
+        assert_eq!(m.percentile(99.0), Some((&99, &())));
+        assert_eq!(m.percentile(100.0), Some((&100, &())));
+
+        let empty: FlatMap<int, ()> = FlatMap::new();
+        assert!(empty.percentile(50.0).is_none());
+    }
+
+    #[test]
+    fn test_weighted_percentile() {
+        // latency bucket => count
+        let m = FlatMap::from_sorted_vec(~[(1, 90u), (5, 9u), (50, 1u), (100, 0u)]);
+        assert_eq!(m.weighted_percentile(50.0, |&c| c), Some(&1));
+        assert_eq!(m.weighted_percentile(90.0, |&c| c), Some(&1));
+        assert_eq!(m.weighted_percentile(99.0, |&c| c), Some(&5));
+        assert_eq!(m.weighted_percentile(100.0, |&c| c), Some(&50));
+        assert!(m.weighted_percentile(50.0, |_| 0).is_none());
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_percentile_out_of_range() {
+        let m = FlatMap::from_sorted_vec(~[(1, 1)]);
+        m.percentile(101.0);
+    }
+}
+
+#[cfg(test)]
+mod test_set {
+    use super::FlatSet;
+
+    #[test]
+    fn test_insert_remove() {
+        let mut s = FlatSet::new();
+        assert!(s.is_empty());
+        assert!(s.insert(5));
+        assert!(s.insert(1));
+        assert!(!s.insert(5));
+        assert_eq!(s.len(), 2);
+        assert!(s.contains(&1));
+        assert!(s.contains(&5));
+        assert!(!s.contains(&3));
+        assert!(s.remove(&1));
+        assert!(!s.remove(&1));
+        assert!(!s.contains(&1));
+        assert_eq!(s.len(), 1);
+    }
+
+    #[test]
+    fn test_iterate() {
+        let s: FlatSet<int> = [9, 3, 7, 1, 3].iter().map(|&x| x).collect();
+        let v = s.iter().map(|&x| x).collect::<~[int]>();
+        assert_eq!(v, ~[1, 3, 7, 9]);
+        let v = s.move_iter().collect::<~[int]>();
+        assert_eq!(v, ~[1, 3, 7, 9]);
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut s: FlatSet<int> = range(0, 1000).map(|i| (i * 37) % 500).collect();
+        assert_eq!(s.len(), 500);
+        s.extend(&mut range(0, 1000).map(|i| 999 - i));
+        assert_eq!(s.len(), 1000);
+        let v = s.iter().map(|&x| x).collect::<~[int]>();
+        assert_eq!(v, range(0, 1000).collect::<~[int]>());
+    }
+
+    fn check(a: &[int], b: &[int], expected: &[int],
+             f: &fn(&FlatSet<int>, &FlatSet<int>, f: &fn(&int) -> bool) -> bool) {
+        let set_a: FlatSet<int> = a.iter().map(|&x| x).collect();
+        let set_b: FlatSet<int> = b.iter().map(|&x| x).collect();
+        let mut i = 0;
+        do f(&set_a, &set_b) |x| {
+            assert_eq!(*x, expected[i]);
+            i += 1;
+            true
+        };
+        assert_eq!(i, expected.len());
+    }
+
+    #[test]
+    fn test_difference() {
+        fn check_difference(a: &[int], b: &[int], expected: &[int]) {
+            check(a, b, expected, |x, y, f| x.difference(y).advance(f))
+        }
+
+        check_difference([], [], []);
+        check_difference([1, 12], [], [1, 12]);
+        check_difference([], [1, 2, 3, 9], []);
+        check_difference([1, 3, 5, 9, 11], [3, 9], [1, 5, 11]);
+        check_difference([-5, 11, 22, 33, 40, 42], [-12, -5, 14, 23, 34, 38, 39, 50],
+                         [11, 22, 33, 40, 42]);
+    }
+
+    #[test]
+    fn test_symmetric_difference() {
+        fn check_symmetric_difference(a: &[int], b: &[int], expected: &[int]) {
+            check(a, b, expected, |x, y, f| x.symmetric_difference(y).advance(f))
+        }
 
-impl<K: Ord + TotalOrd, V: Ord> Ord for FlatMap<K, V> {
-    #[inline]
-    fn lt(&self, other: &FlatMap<K, V>) -> bool { lt(self, other) }
-    #[inline]
-    fn le(&self, other: &FlatMap<K, V>) -> bool { !lt(other, self) }
-    #[inline]
-    fn ge(&self, other: &FlatMap<K, V>) -> bool { !lt(self, other) }
-    #[inline]
-    fn gt(&self, other: &FlatMap<K, V>) -> bool { lt(other, self) }
-}
+        check_symmetric_difference([], [], []);
+        check_symmetric_difference([1, 2, 3], [2], [1, 3]);
+        check_symmetric_difference([2], [1, 2, 3], [1, 3]);
+        check_symmetric_difference([1, 3, 5, 9, 11], [-2, 3, 9, 14, 22],
+                                   [-2, 1, 5, 11, 14, 22]);
+    }
 
-impl<K: TotalOrd, V> Map<K, V> for FlatMap<K, V> {
-    fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
-        match self.data.bsearch(|&(ref k,_)|{k.cmp(key)}) {
-            None => None,
-            Some(idx) => {
-                let (_, ref v) = self.data[idx];
-                Some(v)
-            }
+    #[test]
+    fn test_intersection() {
+        fn check_intersection(a: &[int], b: &[int], expected: &[int]) {
+            check(a, b, expected, |x, y, f| x.intersection(y).advance(f))
         }
+
+        check_intersection([], [], []);
+        check_intersection([1, 2, 3], [], []);
+        check_intersection([], [1, 2, 3], []);
+        check_intersection([2], [1, 2, 3], [2]);
+        check_intersection([1, 2, 3], [2], [2]);
+        check_intersection([11, 1, 3, 77, 103, 5, -5], [2, 11, 77, -9, -42, 5, 3],
+                           [3, 5, 11, 77]);
     }
 
-    fn contains_key(&self, key: &K) -> bool {
-        match self.find(key) {
-            Some(_) => true,
-            None => false
+    #[test]
+    fn test_union() {
+        fn check_union(a: &[int], b: &[int], expected: &[int]) {
+            check(a, b, expected, |x, y, f| x.union(y).advance(f))
         }
+
+        check_union([], [], []);
+        check_union([1, 2, 3], [2], [1, 2, 3]);
+        check_union([2], [1, 2, 3], [1, 2, 3]);
+        check_union([1, 3, 5, 9, 11, 16, 19, 24], [-2, 1, 5, 9, 13, 19],
+                    [-2, 1, 3, 5, 9, 11, 13, 16, 19, 24]);
+    }
+
+    #[test]
+    fn test_subset_disjoint() {
+        let a: FlatSet<int> = [1, 3, 5].iter().map(|&x| x).collect();
+        let b: FlatSet<int> = [1, 2, 3, 4, 5].iter().map(|&x| x).collect();
+        let c: FlatSet<int> = [2, 4].iter().map(|&x| x).collect();
+        assert!(a.is_subset(&b));
+        assert!(!b.is_subset(&a));
+        assert!(b.is_superset(&a));
+        assert!(a.is_disjoint(&c));
+        assert!(!b.is_disjoint(&c));
     }
 }
 
-impl<K: TotalOrd, V> MutableMap<K, V> for FlatMap<K, V> {
-    fn find_mut<'a>(&'a mut self, key: &K) -> Option<&'a mut V> {
-        match self.data.bsearch(|&(ref k,_)|{k.cmp(key)}) {
-            None => None,
-            Some(idx) => {
-                let (_, ref mut v) = self.data[idx];
-                Some(v)
-            }
+#[cfg(test)]
+mod test_soa {
+    use super::{SoaFlatMap, KeepFirst, MergeWith, Included, Excluded};
+    use super::{SoaOccupied, SoaVacant};
+
+    #[test]
+    fn test_insert_find() {
+        let mut m = SoaFlatMap::new();
+        assert!(m.insert(5, 50));
+        assert!(m.insert(1, 10));
+        assert!(m.insert(3, 30));
+        assert!(!m.insert(3, 31));
+        assert_eq!(m.len(), 3);
+        assert_eq!(*m.get(&3), 31);
+        assert_eq!(m.find(&2), None);
+        *m.get_mut(&1) += 1;
+        assert_eq!(m.find(&1), Some(&11));
+        assert_eq!(m.swap(5, 51), Some(50));
+        assert_eq!(m.pop(&5), Some(51));
+        assert_eq!(m.pop(&5), None);
+        assert!(!m.contains_key(&5));
+    }
+
+    #[test]
+    fn test_mangle() {
+        let mut m: SoaFlatMap<int,int> = SoaFlatMap::new();
+        assert_eq!(*m.find_or_insert(1, 2), 2);
+        assert_eq!(*m.find_or_insert(1, 3), 2);
+        assert_eq!(*m.find_or_insert_with(2, |_| 4), 4);
+        assert_eq!(*m.insert_or_update_with(2, 0, |_,x| *x+=1), 5);
+    }
+
+    #[test]
+    fn test_iterators() {
+        let mut m = SoaFlatMap::from_vec(~[(3, 'c'), (1, 'a'), (2, 'b'), (1, 'z')], KeepFirst);
+        let v = m.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, char)]>();
+        assert_eq!(v, ~[(1, 'a'), (2, 'b'), (3, 'c')]);
+        let v = m.iter().invert().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(v, ~[3, 2, 1]);
+        let v = m.range(Excluded(&1), Included(&3)).map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(v, ~[2, 3]);
+        for (_, v) in m.mut_iter() {
+            *v = 'x';
         }
+        assert!(m.values().all(|&v| v == 'x'));
+        let v = m.move_iter().collect::<~[(int, char)]>();
+        assert_eq!(v, ~[(1, 'x'), (2, 'x'), (3, 'x')]);
     }
 
-    fn swap(&mut self, k: K, v: V) -> Option<V> {
-        // work around https://github.com/mozilla/rust/issues/6393
-        let self2: &mut FlatMap<K, V> = unsafe {
-            cast::transmute_copy(&self)
-        };
-        match self.find_mut(&k) {
-            Some(val) => {
-                Some(replace(val, v))
+    #[test]
+    fn test_extend() {
+        let mut m: SoaFlatMap<int, int> = range(0, 10).map(|i| (i * 2, i)).collect();
+        m.extend(&mut range(0, 10).map(|i| (i * 3, -i)));
+        assert_eq!(m.len(), 10 + 10 - 4);
+        assert_eq!(m.find(&6), Some(&-2));
+        assert_eq!(m.find(&4), Some(&2));
+        assert_eq!(m.find(&9), Some(&-3));
+        assert!(m.keys().zip(m.keys().skip(1)).all(|(a, b)| a < b));
+    }
+
+    #[test]
+    fn test_find_equiv() {
+        let mut m = SoaFlatMap::new();
+        m.insert(~"foo", 1);
+        m.insert(~"bar", 2);
+        assert_eq!(m.find_equiv(&("foo")), Some(&1));
+        assert_eq!(m.find_equiv(&("bar")), Some(&2));
+        assert_eq!(m.find_equiv(&("baz")), None);
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut m: SoaFlatMap<int, int> = SoaFlatMap::new();
+        assert_eq!(*m.entry(3).or_insert(30), 30);
+        assert_eq!(*m.entry(3).or_insert(31), 30);
+        assert_eq!(*m.entry(1).or_insert_with(|k| *k * 10), 10);
+        assert_eq!(*m.entry(1).and_modify(|_, v| *v += 1).or_insert(0), 11);
+        match m.entry(3) {
+            SoaOccupied(e) => {
+                let mut e = e;
+                assert_eq!(*e.key(), 3);
+                assert_eq!(e.insert(32), 30);
+                assert_eq!(*e.get(), 32);
+                assert_eq!(e.remove(), 32);
             }
-            None => {
-                let index = lower_bound_index(self2.data, &k);
-                self2.data.insert(index, (k, v));
-                None
+            SoaVacant(_) => fail2!(),
+        }
+        match m.entry(2) {
+            SoaOccupied(_) => fail2!(),
+            SoaVacant(e) => {
+                assert_eq!(*e.key(), 2);
+                *e.insert(20) += 1;
             }
         }
+        let v = m.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, int)]>();
+        assert_eq!(v, ~[(1, 11), (2, 21)]);
     }
 
-    fn pop(&mut self, key: &K) -> Option<V> {
-        let mut index: Option<uint> = None;
-        for (i, &(ref k, _)) in self.data.mut_iter().enumerate() {
-            if key.cmp(k) == Equal {
-                index = Some(i);
-                break;
-            }
-        }
+    #[test]
+    fn test_from_iter_with() {
+        let xs = ~[(2, 1), (1, 2), (2, 3)];
+        let m = SoaFlatMap::from_iter_with(&mut xs.move_iter(), MergeWith(|_, a, b| a + b));
+        let v = m.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, int)]>();
+        assert_eq!(v, ~[(1, 2), (2, 4)]);
+    }
 
-        match index {
-            Some(i) => {
-                let (_, v) = self.data.remove(i);
-                Some(v)
-            }
-            None => None
+    #[test]
+    fn test_ord() {
+        let a = SoaFlatMap::from_sorted_vec(~[(1, 1), (2, 2)]);
+        let b = SoaFlatMap::from_sorted_vec(~[(1, 1), (2, 3)]);
+        let c = SoaFlatMap::from_sorted_vec(~[(1, 1)]);
+        assert!(a < b);
+        assert!(b > a);
+        assert!(c < a);
+        assert!(a <= a.clone());
+        assert!(!(a < a.clone()));
+        assert_eq!(a.cmp(&b), Less);
+        assert!(a.equals(&a.clone()));
+    }
+
+    #[test]
+    fn test_rank_select() {
+        let mut m: SoaFlatMap<int, int> = range(0, 10).map(|i| (i * 10, i)).collect();
+        assert_eq!(m.rank(&35), 4);
+        assert_eq!(m.select(4), Some((&40, &4)));
+        assert_eq!(m.select(10), None);
+        match m.nth_mut(0) {
+            Some((_, v)) => *v = 100,
+            None => fail2!("missing pair"),
         }
+        assert_eq!(m[0], 100);
+        assert_eq!(m.percentile(50.0), Some((&40, &4)));
+        assert_eq!(m.weighted_percentile(100.0, |_| 1), Some(&90));
     }
-}
 
-/// FlatMap iterator
-pub struct FlatMapIterator<'self, K, V> {
-    priv iter: vec::VecIterator<'self, (K, V)>,
-}
+    #[test]
+    fn test_bulk_edits() {
+        let mut m: SoaFlatMap<int, int> = range(0, 20).map(|i| (i, -i)).collect();
+        m.retain(|k, _| k % 2 == 0);
+        assert_eq!(m.len(), 10);
+        let drained = m.drain_range(Included(&4), Excluded(&10));
+        assert_eq!(drained, ~[(4, -4), (6, -6), (8, -8)]);
+        let tail = m.split_off(&12);
+        assert_eq!(tail.keys().map(|&k| k).collect::<~[int]>(), ~[12, 14, 16, 18]);
+        assert_eq!(m.keys().map(|&k| k).collect::<~[int]>(), ~[0, 2, 10]);
+        m.append(tail);
+        m.truncate_front(5);
+        m.truncate_back(4);
+        assert_eq!(m.keys().map(|&k| k).collect::<~[int]>(), ~[10, 12, 14, 16]);
+        let m = m.merge(SoaFlatMap::from_sorted_vec(~[(12, 1), (13, 0)]), |_, a, b| a + b);
+        assert_eq!(m.values().map(|&v| v).collect::<~[int]>(), ~[-10, -11, 0, -14, -16]);
+    }
 
-impl<'self, K, V> Iterator<(&'self K, &'self V)> for FlatMapIterator<'self, K, V> {
-    #[inline]
-    fn next(&mut self) -> Option<(&'self K, &'self V)> {
-        match self.iter.next() {
-            Some(&(ref k, ref v)) => Some((k, v)),
-            None => None
+    #[test]
+    fn test_both_ends() {
+        let mut m = SoaFlatMap::from_sorted_vec(range(0, 5).map(|i| (i, i)).collect());
+        let v = m.rev_iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(v, ~[4, 3, 2, 1, 0]);
+        for (k, v) in m.mut_rev_iter() {
+            *v = 4 - *k;
         }
+        let mut it = m.move_iter();
+        assert_eq!(it.next_back(), Some((4, 0)));
+        assert_eq!(it.next(), Some((0, 4)));
+        assert_eq!(it.invert().collect::<~[(int, int)]>(), ~[(3, 1), (2, 2), (1, 3)]);
     }
 
-    #[inline]
-    fn size_hint(&self) -> (uint, Option<uint>) {
-        return self.iter.size_hint();
+    #[test]
+    fn test_cursors() {
+        let mut m = SoaFlatMap::from_sorted_vec(range(0, 50).map(|i| (i * 2, i)).collect());
+        {
+            let mut c = m.cursor();
+            assert!(c.seek(&40));
+            assert_eq!(c.get(), Some((&40, &20)));
+            assert!(!c.seek(&41));
+            assert_eq!(c.index(), 21);
+            assert!(c.move_prev());
+            assert!(c.seek(&2));
+            assert_eq!(c.index(), 1);
+        }
+        {
+            let mut c = m.cursor_mut();
+            assert!(!c.seek(&3));
+            c.insert(3, -1);
+            assert_eq!(c.get(), Some((&3, &-1)));
+            assert!(c.move_next());
+            match c.get_mut() {
+                Some((_, v)) => *v = 7,
+                None => fail2!("missing pair"),
+            }
+            assert_eq!(c.remove(), Some((4, 7)));
+            assert!(c.seek(&98));
+            assert!(c.move_next());
+            assert_eq!(c.remove(), None);
+        }
+        assert_eq!(m.len(), 50);
+        assert_eq!(m.find(&3), Some(&-1));
+        assert_eq!(m.find(&4), None);
     }
 }
 
-/// FlatMap mutable values iterator
-pub struct FlatMapMutIterator<'self, K, V> {
-    priv iter: vec::VecMutIterator<'self, (K, V)>,
-}
+#[cfg(test)]
+mod test_frozen {
+    use super::FlatMap;
 
-impl<'self, K, V> Iterator<(&'self K, &'self mut V)> for FlatMapMutIterator<'self, K, V> {
-    #[inline]
-    fn next(&mut self) -> Option<(&'self K, &'self mut V)> {
-        match self.iter.next() {
-            Some(&(ref k, ref mut v)) => Some((k, v)),
-            None => None
+    #[test]
+    fn test_find() {
+        for n in range(0, 70) {
+            let m = FlatMap::from_sorted_vec(range(0, n).map(|i| (i * 2, i)).collect());
+            let m = m.freeze();
+            assert_eq!(m.len(), n as uint);
+            for i in range(0, n) {
+                assert_eq!(m.find(&(i * 2)), Some(&i));
+                assert!(m.find(&(i * 2 + 1)).is_none());
+            }
+            assert!(m.find(&-1).is_none());
         }
     }
 
-    #[inline]
-    fn size_hint(&self) -> (uint, Option<uint>) {
-        return self.iter.size_hint();
+    #[test]
+    fn test_iterate() {
+        for n in range(0, 40) {
+            let m = FlatMap::from_sorted_vec(range(0, n).map(|i| (i, -i)).collect());
+            let m = m.freeze();
+            let v = m.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, int)]>();
+            assert_eq!(v, range(0, n).map(|i| (i, -i)).collect::<~[(int, int)]>());
+            let v = m.iter().invert().map(|(&k, _)| k).collect::<~[int]>();
+            assert_eq!(v, range(0, n).invert().collect::<~[int]>());
+        }
     }
-}
-
-/// FlatMap move iterator
-pub struct FlatMapMoveIterator<K, V> {
-    priv iter: vec::MoveRevIterator<(K, V)>,
-}
 
-impl<K, V> Iterator<(K, V)> for FlatMapMoveIterator<K, V> {
-    #[inline]
-    fn next(&mut self) -> Option<(K, V)> {
-        self.iter.next()
+    #[test]
+    fn test_iterate_both_ends() {
+        let m = FlatMap::from_sorted_vec(range(0, 5).map(|i| (i, ())).collect()).freeze();
+        let mut it = m.iter();
+        assert_eq!(it.next(), Some((&0, &())));
+        assert_eq!(it.next_back(), Some((&4, &())));
+        assert_eq!(it.next(), Some((&1, &())));
+        assert_eq!(it.next_back(), Some((&3, &())));
+        assert_eq!(it.size_hint(), (1, Some(1)));
+        assert_eq!(it.next(), Some((&2, &())));
+        assert_eq!(it.next_back(), None);
+        assert_eq!(it.next(), None);
     }
 
-    #[inline]
-    fn size_hint(&self) -> (uint, Option<uint>) {
-        return self.iter.size_hint();
+    #[test]
+    fn test_thaw() {
+        let m: FlatMap<int, int> =
+            range(0, 100).map(|i| ((i * 37) % 100, i)).collect();
+        let copy = m.clone();
+        assert_eq!(FlatMap::from_flatmap_by(m.freeze().thaw()), copy);
     }
 }
 
 #[cfg(test)]
-mod test {
-    use super::FlatMap;
+mod test_small {
+    use super::SmallFlatMap;
+
+    type Small4 = SmallFlatMap<int, int, [Option<(int, int)>, ..4]>;
 
     #[test]
-    fn test_container_trait() {
-        let m: FlatMap<int, int> = FlatMap::with_capacity(10);
-        assert!(10 == m.capacity());
-        assert!(0 == m.len());
+    fn test_inline() {
+        let mut m: Small4 = SmallFlatMap::new();
+        assert_eq!(m.inline_capacity(), 4);
+        assert!(m.insert(3, 30));
+        assert!(m.insert(1, 10));
+        assert!(m.insert(4, 40));
+        assert!(m.insert(2, 20));
+        assert!(!m.insert(2, 21));
+        assert!(!m.spilled());
+        assert_eq!(m.len(), 4);
+        let v = m.iter().map(|(&k, &v)| (k, v)).collect::<~[(int, int)]>();
+        assert_eq!(v, ~[(1, 10), (2, 21), (3, 30), (4, 40)]);
+        assert_eq!(m.pop(&1), Some(10));
+        assert_eq!(m.pop(&1), None);
+        assert_eq!(*m.get(&4), 40);
+        assert_eq!(m.len(), 3);
     }
 
     #[test]
-    fn test_map_trait() {
-        let m: FlatMap<int, int> = FlatMap::new();
-        assert!(m.find(&0) == None);
-        assert!(m.contains_key(&0) == false);
+    fn test_spill() {
+        let mut m: Small4 = SmallFlatMap::new();
+        for i in range(0, 10) {
+            assert!(m.insert(9 - i, i));
+            assert_eq!(m.spilled(), i >= 4);
+        }
+        assert_eq!(m.len(), 10);
+        for i in range(0, 10) {
+            assert_eq!(m.find(&(9 - i)), Some(&i));
+        }
+        let keys = m.iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, range(0, 10).collect::<~[int]>());
+        m.clear();
+        assert!(!m.spilled());
+        assert!(m.is_empty());
     }
 
     #[test]
-    fn test_mutable_map_trait() {
-        let mut m: FlatMap<int, int> = FlatMap::new();
-        assert!(m.contains_key(&0) == false);
-        m.insert(0, 1);
-        assert!(m.contains_key(&0));
-        m.insert(1, 2);
-        assert!(m.len() == 2);
-        assert!(m.contains_key(&1));
-        m.pop(&0);
-        assert!(m.contains_key(&0) == false);
-        assert!(m.contains_key(&1));
-        assert!(m.len() == 1);
-        m.insert(1, 3);
-        match m.find(&1) {
-            Some(v) => assert_eq!(3, *v),
-            None => fail2!("No entry found for key: 1"),
-        }
+    fn test_mangle() {
+        let mut m: Small4 = SmallFlatMap::new();
+        assert_eq!(*m.find_or_insert(1, 2), 2);
+        assert_eq!(*m.find_or_insert(1, 3), 2);
+        assert_eq!(*m.find_or_insert_with(2, |_| 4), 4);
+        assert_eq!(*m.insert_or_update_with(2, 0, |_,x| *x+=1), 5);
+        assert_eq!(m.swap(2, 7), Some(5));
+        assert_eq!(m.swap(0, 1), None);
+        *m.get_mut(&0) += 1;
+        assert_eq!(m.find(&0), Some(&2));
+    }
 
-        for (k, v) in m.iter() {
-            assert_eq!((1, 3), (*k, *v));
+    #[test]
+    fn test_iterators() {
+        for n in [2, 8].iter() {
+            let mut m: Small4 = range(0, *n).map(|i| (i, i)).collect();
+            for (_, v) in m.mut_iter() {
+                *v *= 10;
+            }
+            let copy = m.clone();
+            assert!(copy == m);
+            let v = m.move_iter().collect::<~[(int, int)]>();
+            assert_eq!(v, range(0, *n).map(|i| (i, i * 10)).collect::<~[(int, int)]>());
         }
+    }
+}
 
-        for (_, v) in m.mut_iter() {
-            *v = 1;
+#[cfg(test)]
+mod test_buffered {
+    use super::{BufferedFlatMap, isqrt};
+
+    #[test]
+    fn test_isqrt() {
+        for n in range(0u, 1000) {
+            let r = isqrt(n);
+            assert!(r * r <= n);
+            assert!((r + 1) * (r + 1) > n);
         }
+    }
 
-        for (k, v) in m.iter() {
-            assert_eq!((1, 1), (*k, *v));
+    #[test]
+    fn test_insert_find() {
+        let mut m = BufferedFlatMap::with_threshold(4);
+        for i in range(0, 100) {
+            assert!(m.insert((i * 37) % 100, i));
+            assert!(m.staged() <= 4);
+            assert_eq!(m.len(), (i + 1) as uint);
         }
+        for i in range(0, 100) {
+            assert_eq!(m.find(&((i * 37) % 100)), Some(&i));
+        }
+        assert!(m.find(&100).is_none());
 
-        assert_eq!((1u, Some(1u)), m.iter().size_hint());
-        m.insert(2, 2);
-        m.insert(3, 3);
-        assert_eq!((3u, Some(3u)), m.iter().size_hint());
+        m.flush();
+        assert_eq!(m.staged(), 0);
+        assert_eq!(m.len(), 100);
+        for i in range(0, 100) {
+            assert_eq!(m.find(&((i * 37) % 100)), Some(&i));
+        }
     }
-}
 
-#[cfg(test)]
-mod test_map {
-    use super::FlatMap;
+    #[test]
+    fn test_with_capacity() {
+        let m: BufferedFlatMap<int, int> = BufferedFlatMap::with_capacity(10);
+        assert!(m.capacity() >= 10);
+        assert_eq!(m.len(), 0);
+        assert_eq!(m.staged(), 0);
+    }
 
     #[test]
-    fn test_create_capacity_zero() {
-        let mut m = FlatMap::with_capacity(0);
-        assert!(m.insert(1, 1));
+    fn test_from_iter() {
+        let mut m: BufferedFlatMap<int, int> = range(0, 50).map(|i| (49 - i, i)).collect();
+        assert_eq!(m.len(), 50);
+        assert_eq!(m.staged(), 0);
+        assert!(m.insert(50, 0));
+        assert_eq!(m.staged(), 1);
+        let keys = m.iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, range(0, 51).collect::<~[int]>());
     }
 
     #[test]
-    fn test_insert() {
-        let mut m = FlatMap::new();
-        assert!(m.insert(1, 2));
-        assert!(m.insert(2, 4));
-        assert_eq!(*m.get(&1), 2);
-        assert_eq!(*m.get(&2), 4);
+    fn test_overwrite_and_pop() {
+        let mut m = BufferedFlatMap::with_threshold(2);
+        m.insert(1, 1);
+        m.insert(2, 2);
+        m.insert(3, 3);
+        m.insert(4, 4);
+        assert!(m.staged() > 0);
+        assert!(!m.insert(1, 10));
+        assert!(!m.insert(4, 40));
+        assert_eq!(*m.get(&1), 10);
+        assert_eq!(*m.get(&4), 40);
+        *m.get_mut(&3) += 30;
+        assert_eq!(m.find(&3), Some(&33));
+        assert_eq!(m.pop(&4), Some(40));
+        assert_eq!(m.pop(&1), Some(10));
+        assert_eq!(m.pop(&1), None);
+        assert_eq!(m.len(), 2);
     }
 
     #[test]
-    fn test_find_mut() {
-        let mut m = FlatMap::new();
-        assert!(m.insert(1, 12));
-        assert!(m.insert(2, 8));
-        assert!(m.insert(5, 14));
-        let new = 100;
-        match m.find_mut(&5) {
-            None => fail2!(), Some(x) => *x = new
+    fn test_iterate() {
+        let mut m = BufferedFlatMap::with_threshold(8);
+        for i in range(0, 20) {
+            m.insert(19 - i, i);
         }
-        assert_eq!(m.find(&5), Some(&new));
+        assert!(m.staged() > 0);
+        assert_eq!(m.iter().size_hint(), (20, Some(20)));
+        let keys = m.iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, range(0, 20).collect::<~[int]>());
+
+        let map = m.into_flatmap();
+        assert_eq!(map.len(), 20);
+        assert_eq!(map.find(&0), Some(&19));
     }
+}
+
+#[cfg(test)]
+mod test_multi {
+    use super::FlatMultiMap;
 
     #[test]
-    fn test_insert_overwrite() {
-        let mut m = FlatMap::new();
-        assert!(m.insert(1, 2));
-        assert_eq!(*m.get(&1), 2);
-        assert!(!m.insert(1, 3));
-        assert_eq!(*m.get(&1), 3);
+    fn test_insert_equal_range() {
+        let mut m = FlatMultiMap::new();
+        m.insert(2, 'a');
+        m.insert(1, 'b');
+        m.insert(2, 'c');
+        m.insert(3, 'd');
+        m.insert(2, 'e');
+        assert_eq!(m.len(), 5);
+        assert_eq!(m.count(&2), 3);
+        assert_eq!(m.count(&4), 0);
+        assert!(m.contains_key(&3));
+        let v = m.equal_range(&2).map(|(_, &v)| v).collect::<~[char]>();
+        assert_eq!(v, ~['a', 'c', 'e']);
+        let keys = m.iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[1, 2, 2, 2, 3]);
     }
 
     #[test]
-    fn test_insert_conflicts() {
-        let mut m = FlatMap::with_capacity(4);
-        assert!(m.insert(1, 2));
-        assert!(m.insert(5, 3));
-        assert!(m.insert(9, 4));
-        assert_eq!(*m.get(&9), 4);
-        assert_eq!(*m.get(&5), 3);
-        assert_eq!(*m.get(&1), 2);
+    fn test_remove() {
+        let mut m: FlatMultiMap<int, int> = range(0, 30).map(|i| (i % 3, i)).collect();
+        assert_eq!(m.remove_one(&1), Some(1));
+        assert_eq!(m.remove_all(&1), range(1, 10).map(|i| i * 3 + 1).collect::<~[int]>());
+        assert_eq!(m.remove_one(&1), None);
+        assert_eq!(m.remove_all(&1), ~[]);
+        assert_eq!(m.len(), 20);
+        let keys = m.iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, ~[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2]);
     }
 
     #[test]
-    fn test_conflict_remove() {
-        let mut m = FlatMap::with_capacity(4);
-        assert!(m.insert(1, 2));
-        assert!(m.insert(5, 3));
-        assert!(m.insert(9, 4));
-        assert!(m.remove(&1));
-        assert_eq!(*m.get(&9), 4);
-        assert_eq!(*m.get(&5), 3);
+    fn test_groups() {
+        let mut m: FlatMultiMap<~str, int> = FlatMultiMap::new();
+        m.extend(&mut (~[(~"b", 1), (~"a", 2), (~"b", 3)]).move_iter());
+        m.extend(&mut (~[(~"b", 4), (~"c", 5)]).move_iter());
+        let groups = m.group_iter().map(|(k, vs)| (k.clone(), vs.map(|&v| v).collect::<~[int]>()))
+                      .collect::<~[(~str, ~[int])]>();
+        assert_eq!(groups, ~[(~"a", ~[2]), (~"b", ~[1, 3, 4]), (~"c", ~[5])]);
     }
+}
 
-    #[test]
-    fn test_is_empty() {
-        let mut m = FlatMap::with_capacity(4);
-        assert!(m.insert(1, 2));
-        assert!(!m.is_empty());
-        assert!(m.remove(&1));
-        assert!(m.is_empty());
+#[cfg(test)]
+mod test_interval {
+    use super::FlatIntervalMap;
+
+    fn ranges(m: &FlatIntervalMap<int, char>) -> ~[(int, int, char)] {
+        m.iter().map(|(&s, &e, &v)| (s, e, v)).collect()
     }
 
     #[test]
-    fn test_pop() {
-        let mut m = FlatMap::new();
-        m.insert(1, 2);
-        assert_eq!(m.pop(&1), Some(2));
-        assert_eq!(m.pop(&1), None);
+    fn test_insert_split() {
+        let mut m = FlatIntervalMap::new();
+        m.insert_range(0, 10, 'a');
+        m.insert_range(20, 30, 'b');
+        m.insert_range(5, 25, 'c');
+        assert_eq!(ranges(&m), ~[(0, 5, 'a'), (5, 25, 'c'), (25, 30, 'b')]);
+        m.insert_range(10, 12, 'd');
+        assert_eq!(ranges(&m), ~[(0, 5, 'a'), (5, 10, 'c'), (10, 12, 'd'), (12, 25, 'c'),
+                                 (25, 30, 'b')]);
+        m.insert_range(-5, 40, 'e');
+        assert_eq!(ranges(&m), ~[(-5, 40, 'e')]);
+        m.insert_range(3, 3, 'f');
+        assert_eq!(m.len(), 1);
     }
 
     #[test]
-    fn test_swap() {
-        let mut m = FlatMap::new();
-        assert_eq!(m.swap(1, 2), None);
-        assert_eq!(m.swap(1, 3), Some(2));
-        assert_eq!(m.swap(1, 4), Some(3));
+    fn test_coalesce() {
+        let mut m = FlatIntervalMap::new();
+        m.insert_range(0, 10, 'a');
+        m.insert_range(10, 20, 'a');
+        assert_eq!(ranges(&m), ~[(0, 20, 'a')]);
+        m.insert_range(30, 40, 'a');
+        m.insert_range(20, 30, 'a');
+        assert_eq!(ranges(&m), ~[(0, 40, 'a')]);
+        m.insert_range(5, 15, 'a');
+        assert_eq!(ranges(&m), ~[(0, 40, 'a')]);
+        m.insert_range(40, 50, 'b');
+        assert_eq!(m.len(), 2);
     }
 
     #[test]
-    fn test_find_or_insert() {
-        let mut m: FlatMap<int,int> = FlatMap::new();
-        assert_eq!(*m.find_or_insert(1, 2), 2);
-        assert_eq!(*m.find_or_insert(1, 3), 2);
+    fn test_find_overlapping() {
+        let mut m = FlatIntervalMap::new();
+        m.insert_range(0, 10, 'a');
+        m.insert_range(20, 30, 'b');
+        m.insert_range(30, 35, 'c');
+        assert_eq!(m.find(&-1), None);
+        assert_eq!(m.find(&0), Some(&'a'));
+        assert_eq!(m.find(&9), Some(&'a'));
+        assert_eq!(m.find(&10), None);
+        assert_eq!(m.find(&30), Some(&'c'));
+        assert_eq!(m.find(&35), None);
+
+        let v = m.overlapping(&9, &21).map(|(_, _, &v)| v).collect::<~[char]>();
+        assert_eq!(v, ~['a', 'b']);
+        let v = m.overlapping(&10, &20).map(|(_, _, &v)| v).collect::<~[char]>();
+        assert_eq!(v, ~[]);
+        let v = m.overlapping(&29, &100).invert().map(|(_, _, &v)| v).collect::<~[char]>();
+        assert_eq!(v, ~['c', 'b']);
+        assert!(m.overlapping(&5, &5).next().is_none());
     }
 
     #[test]
-    fn test_find_or_insert_with() {
-        let mut m: FlatMap<int,int> = FlatMap::new();
-        assert_eq!(*m.find_or_insert_with(1, |_| 2), 2);
-        assert_eq!(*m.find_or_insert_with(1, |_| 3), 2);
+    fn test_remove_range() {
+        let mut m = FlatIntervalMap::new();
+        m.insert_range(0, 10, 'a');
+        m.insert_range(10, 20, 'b');
+        m.remove_range(&5, &15);
+        assert_eq!(ranges(&m), ~[(0, 5, 'a'), (15, 20, 'b')]);
+        m.remove_range(&2, &3);
+        assert_eq!(ranges(&m), ~[(0, 2, 'a'), (3, 5, 'a'), (15, 20, 'b')]);
+        m.remove_range(&-10, &100);
+        assert!(m.is_empty());
     }
+}
+
+#[cfg(test)]
+mod test_persistent {
+    use std::task;
+    use std::vec;
+    use super::{FlatMap, PersistentFlatMap, SharedChunk, PERSISTENT_CHUNK_SIZE};
 
     #[test]
-    fn test_insert_or_update_with() {
-        let mut m: FlatMap<int,int> = FlatMap::new();
-        assert_eq!(*m.insert_or_update_with(1, 2, |_,x| *x+=1), 2);
-        assert_eq!(*m.insert_or_update_with(1, 2, |_,x| *x+=1), 3);
+    fn test_insert_find_pop() {
+        let mut m = PersistentFlatMap::new();
+        for i in range(0, 500) {
+            assert!(m.insert((i * 37) % 500, i));
+        }
+        assert!(!m.insert(0, -1));
+        assert_eq!(m.len(), 500);
+        assert!(m.chunk_count() > 500 / PERSISTENT_CHUNK_SIZE);
+        assert_eq!(m.find(&0), Some(&-1));
+        assert_eq!(m.find(&500), None);
+        for i in range(0, 500) {
+            assert!(m.contains_key(&i));
+        }
+        for i in range(0, 500) {
+            if i % 2 == 1 {
+                assert!(m.remove(&i));
+            }
+        }
+        assert!(!m.remove(&1));
+        assert_eq!(m.pop(&10), Some(230));
+        assert_eq!(m.len(), 249);
+        let keys = m.iter().map(|(&k, _)| k).collect::<~[int]>();
+        assert_eq!(keys, range(0, 250).map(|i| i * 2).filter(|&i| i != 10).collect::<~[int]>());
     }
 
     #[test]
-    fn test_move_iter() {
-        let hm = {
-            let mut hm = FlatMap::new();
+    fn test_snapshot() {
+        let original: FlatMap<int, int> = range(0, 1000).map(|i| (i, i)).collect();
+        let mut m = PersistentFlatMap::from_flatmap(original.clone());
+        let snapshot = m.clone();
+        assert!(m.modify(&500, |_, v| *v = -500));
+        assert!(!m.modify(&1000, |_, v| *v = 0));
+        m.insert(1000, 1000);
+        m.pop(&0);
+        m.clear();
+        m.insert(1, 1);
 
-            hm.insert('a', 1);
-            hm.insert('b', 2);
+        assert_eq!(snapshot.len(), 1000);
+        assert_eq!(snapshot.find(&500), Some(&500));
+        assert_eq!(snapshot.find(&1000), None);
+        assert_eq!(snapshot.to_flatmap(), original);
+        assert_eq!(m.len(), 1);
+    }
 
-            hm
+    #[test]
+    fn test_snapshot_send() {
+        let mut m: PersistentFlatMap<int, int> = range(0, 1000).map(|i| (i, i)).collect();
+        let snapshot = m.clone();
+        assert!(m.modify(&0, |_, v| *v = -1));
+        let sum = do task::try {
+            let mut s = snapshot.clone();
+            s.insert(1000, 1000);
+            s.iter().fold(0, |a, (_, &v)| a + v)
         };
-
-        let v = hm.move_iter().collect::<~[(char, int)]>();
-        assert!([('a', 1), ('b', 2)] == v || [('b', 2), ('a', 1)] == v);
+        assert_eq!(sum, Ok(499500 + 1000));
+        assert_eq!(m.find(&0), Some(&-1));
+        assert_eq!(m.find(&1000), None);
     }
 
     #[test]
-    fn test_iterate() {
-        let mut m = FlatMap::with_capacity(4);
-        for i in range(0u, 32) {
-            assert!(m.insert(i, i*2));
-        }
-        let mut observed = 0;
-        for (k, v) in m.iter() {
-            assert_eq!(*v, *k * 2);
-            observed |= (1 << *k);
+    fn test_shared_chunk_copy_on_write() {
+        let mut a = SharedChunk::new(~[(1, 1), (2, 2), (3, 3)]);
+        let unshared = vec::raw::to_ptr(a.get());
+        a.get_mut()[2] = (3, -3);
+        assert_eq!(vec::raw::to_ptr(a.get()), unshared);
+        {
+            let b = a.clone();
+            assert_eq!(vec::raw::to_ptr(b.get()), unshared);
+            a.get_mut()[0] = (1, -1);
+            assert!(vec::raw::to_ptr(a.get()) != unshared);
+            assert_eq!(b.get(), &[(1, 1), (2, 2), (3, -3)]);
+            assert_eq!(a.get(), &[(1, -1), (2, 2), (3, -3)]);
         }
-        assert_eq!(observed, 0xFFFF_FFFF);
+        // once the snapshot is gone the copy is changed in place again
+        let copy = vec::raw::to_ptr(a.get());
+        a.get_mut()[1] = (2, -2);
+        assert_eq!(vec::raw::to_ptr(a.get()), copy);
+        assert_eq!(a.get(), &[(1, -1), (2, -2), (3, -3)]);
+    }
+}
+
+#[cfg(test)]
+mod test_concurrent {
+    use std::comm;
+    use std::task;
+    use super::{FlatMap, ConcurrentFlatMap};
+
+    fn version_map(version: uint) -> FlatMap<uint, uint> {
+        range(0u, 64).map(|k| (k, version)).collect()
     }
 
     #[test]
-    fn test_find() {
-        let mut m = FlatMap::new();
-        assert!(m.find(&1).is_none());
-        m.insert(1, 2);
-        match m.find(&1) {
-            None => fail2!(),
-            Some(v) => assert!(*v == 2)
+    fn test_read_publish() {
+        let m = ConcurrentFlatMap::new();
+        assert_eq!(m.find_copy(&1), None);
+        m.publish(version_map(1));
+        assert_eq!(m.find_copy(&1), Some(1));
+        do m.update |map| {
+            let mut map = map.clone();
+            map.insert(100, 7);
+            map
         }
+        assert_eq!(m.read(|map| map.len()), 65);
+        let other = m.clone();
+        other.publish(FlatMap::new());
+        assert!(m.read(|map| map.is_empty()));
     }
 
     #[test]
-    fn test_eq() {
-        let mut m1 = FlatMap::new();
-        m1.insert(1, 2);
-        m1.insert(2, 3);
-        m1.insert(3, 4);
-
-        let mut m2 = FlatMap::new();
-        m2.insert(1, 2);
-        m2.insert(2, 3);
+    fn test_readers_see_whole_versions() {
+        let nreaders = 4u;
+        let nversions = 200u;
+        let m = ConcurrentFlatMap::from_flatmap(version_map(0));
+        let mut done = ~[];
+        for _ in range(0, nreaders) {
+            let (port, chan) = comm::stream();
+            let (done_port, done_chan) = comm::stream();
+            chan.send(m.clone());
+            do task::spawn_sched(task::SingleThreaded) {
+                let m: ConcurrentFlatMap<uint, uint> = port.recv();
+                let mut last = 0u;
+                while last < nversions {
+                    let version = do m.read |map| {
+                        let version = *map.get(&0);
+                        assert!(map.iter().all(|(_, &v)| v == version));
+                        version
+                    };
+                    assert!(version >= last);
+                    last = version;
+                }
+                done_chan.send(());
+            }
+            done.push(done_port);
+        }
+        for version in range(1, nversions + 1) {
+            m.publish(version_map(version));
+        }
+        for port in done.iter() {
+            port.recv();
+        }
+    }
 
-        assert!(m1 != m2);
+    #[test]
+    fn test_failed_update_releases_lock() {
+        let m = ConcurrentFlatMap::from_flatmap(version_map(1));
+        let (port, chan) = comm::stream();
+        chan.send(m.clone());
+        let result = do task::try {
+            let m: ConcurrentFlatMap<uint, uint> = port.recv();
+            do m.update |_| {
+                fail2!("update failed")
+            }
+        };
+        assert!(result.is_err());
+        m.publish(version_map(2));
+        assert_eq!(m.find_copy(&0), Some(2));
+    }
+}
 
-        m2.insert(3, 4);
+#[cfg(test)]
+#[cfg(unix)]
+mod test_view {
+    use std::cast;
+    use std::io;
+    use std::os;
+    use std::uint;
+    use super::{FlatMap, FlatMapView, Included, Excluded, format_layout};
+    use super::PlainData;
 
-        assert_eq!(m1, m2);
+    fn temp_path(name: &str) -> Path {
+        os::tmpdir().push(format!("flatmap-{}-{}", name, os::getpid()))
     }
 
     #[test]
-    fn test_find_equiv() {
-        let mut m = FlatMap::new();
-
-        let (foo, bar, baz) = (1,2,3);
-        m.insert(~"foo", foo);
-        m.insert(~"bar", bar);
-        m.insert(~"baz", baz);
+    fn test_save_open() {
+        let m: FlatMap<u64, i32> =
+            range(0u64, 1000).map(|i| (i * 3, -(i as i32))).collect();
+        let path = temp_path("save-open");
+        m.save(&path).unwrap();
+        {
+            let view: FlatMapView<u64, i32> = FlatMapView::open(&path).unwrap();
+            assert_eq!(view.len(), 1000);
+            assert_eq!(view.find(&300), Some(&-100));
+            assert_eq!(view.find(&301), None);
+            let keys = view.range(Included(&10), Excluded(&21))
+                           .map(|(&k, _)| k).collect::<~[u64]>();
+            assert_eq!(keys, ~[12, 15, 18]);
+            assert!(view.iter().zip(m.iter()).all(|(a, b)| a == b));
+            let last = view.iter().invert().next();
+            assert_eq!(last, Some((&2997, &-999)));
+            assert_eq!(view.to_flatmap(), m);
+        }
+        os::remove_file(&path);
+    }
 
+    #[test]
+    fn test_open_mismatch() {
+        let m = FlatMap::from_sorted_vec(~[(1u32, 1u8), (2, 2)]);
+        let path = temp_path("mismatch");
+        m.save(&path).unwrap();
+        let view: Result<FlatMapView<u64, u8>, ~str> = FlatMapView::open(&path);
+        assert!(view.is_err());
+        os::remove_file(&path);
 
-        assert_eq!(m.find_equiv(&("foo")), Some(&foo));
-        assert_eq!(m.find_equiv(&("bar")), Some(&bar));
-        assert_eq!(m.find_equiv(&("baz")), Some(&baz));
+        let view: Result<FlatMapView<u32, u8>, ~str> = FlatMapView::open(&path);
+        assert!(view.is_err());
+    }
 
-        assert_eq!(m.find_equiv(&("qux")), None);
+    #[test]
+    fn test_write_to() {
+        let m: FlatMap<u16, u64> = range(0u16, 5).map(|i| (i, i as u64)).collect();
+        let bytes = io::with_bytes_writer(|w| m.write_to(w));
+        let (keys, values, end) = format_layout::<u16, u64>(5).unwrap();
+        assert_eq!(bytes.len(), end);
+        assert_eq!(values % 8, 0);
+        assert_eq!(bytes.slice_to(7), "FLATMAP".as_bytes());
+        assert!(keys < values);
+        assert!(format_layout::<u16, u64>(uint::max_value / 4).is_none());
     }
 
     #[test]
-    fn test_from_iter() {
-        let xs = ~[(1, 1), (2, 2), (3, 3), (4, 4), (5, 5), (6, 6)];
+    fn test_open_bad_len() {
+        let m = FlatMap::from_sorted_vec(~[(1u64, 1u64), (2, 2)]);
+        let mut bytes = io::with_bytes_writer(|w| m.write_to(w));
+        // a length whose sections wrap around the address space
+        let len: [u8, ..8] = unsafe { cast::transmute(0x2000_0000_0000_0001u64) };
+        for (i, &b) in len.iter().enumerate() {
+            bytes[24 + i] = b;
+        }
+        let path = temp_path("bad-len");
+        io::file_writer(&path, [io::Create, io::Truncate]).unwrap().write(bytes);
+        let view: Result<FlatMapView<u64, u64>, ~str> = FlatMapView::open(&path);
+        assert!(view.is_err());
+        os::remove_file(&path);
+    }
 
-        let map: FlatMap<int, int> = xs.iter().map(|&x| x).collect();
+    impl PlainData for ~str {}
 
-        for &(k, v) in xs.iter() {
-            assert_eq!(map.find(&k), Some(&v));
-        }
+    #[test]
+    #[should_fail]
+    fn test_write_to_needs_drop() {
+        let m = FlatMap::from_sorted_vec(~[(1u32, ~"a")]);
+        io::with_bytes_writer(|w| m.write_to(w));
+    }
+
+    #[test]
+    fn test_open_needs_drop() {
+        let m = FlatMap::from_sorted_vec(~[(1u32, 1u32)]);
+        let path = temp_path("needs-drop");
+        m.save(&path).unwrap();
+        let view: Result<FlatMapView<u32, ~str>, ~str> = FlatMapView::open(&path);
+        assert!(view.is_err());
+        os::remove_file(&path);
     }
 }
 
@@ -613,6 +7490,58 @@ macro_rules! bench_find {
     }}
 }
 
+macro_rules! bench_find_large {
+    ($n:expr, $map_type:ident) => {{
+        let size = $n;
+        let mut m = $map_type::new();
+        for i in range(0u, size) {
+            assert!(m.insert(i, [i, ..32]));
+        }
+        let mut rng = rand::weak_rng();
+        do bh.iter {
+            let k: uint = rng.gen_integer_range(0u, size * 2);
+            if k < size {
+                assert!(m.find(&k).is_some());
+            } else {
+                assert!(m.find(&k).is_none());
+            }
+        }
+    }}
+}
+
+macro_rules! bench_find_frozen {
+    ($n:expr) => {{
+        let size = $n;
+        let mut m = FlatMap::new();
+        for i in range(0u, size) {
+            assert!(m.insert(i, i));
+        }
+        let m = m.freeze();
+        let mut rng = rand::weak_rng();
+        do bh.iter {
+            let k: uint = rng.gen_integer_range(0u, size * 2);
+            if k < size {
+                assert!(m.find(&k).is_some());
+            } else {
+                assert!(m.find(&k).is_none());
+            }
+        }
+    }}
+}
+
+macro_rules! bench_find_keys {
+    ($n:expr, $key:ident, $order:expr) => {{
+        let size = $n;
+        let mut m = FlatMapBy::with_comparator($order);
+        m.extend(&mut range(0u, size).map(|i| ($key(i as u64), i)));
+        let mut rng = rand::weak_rng();
+        do bh.iter {
+            let i: uint = rng.gen_integer_range(0u, size);
+            assert!(m.find(&$key(i as u64)).is_some());
+        }
+    }}
+}
+
 macro_rules! bench_insert {
     ($n:expr, $map_type:ident) => {{
         do bh.iter {
@@ -629,9 +7558,14 @@ mod bench {
     use std::hashmap::HashMap;
     use std::rand;
     use std::rand::Rng;
+    use std::comm;
+    use std::task;
+    use std::unstable::atomics::{AtomicBool, Relaxed};
+    use std::unstable::sync::UnsafeArc;
     use extra::treemap::TreeMap;
     use extra::test::BenchHarness;
-    use super::FlatMap;
+    use super::{FlatMap, FlatMapBy, SoaFlatMap, BufferedFlatMap, ConcurrentFlatMap, NaturalOrder};
+    use super::InterpolationSearch;
 
     #[bench]
     fn hashmap_insert_10(bh: &mut BenchHarness) {
@@ -681,6 +7615,38 @@ mod bench {
     }
 
 
+    #[bench]
+    fn soa_flatmap_insert_10(bh: &mut BenchHarness) {
+        bench_insert!(10, SoaFlatMap);
+    }
+
+    #[bench]
+    fn soa_flatmap_insert_100(bh: &mut BenchHarness) {
+        bench_insert!(100, SoaFlatMap);
+    }
+
+    #[bench]
+    fn soa_flatmap_insert_1000(bh: &mut BenchHarness) {
+        bench_insert!(1000, SoaFlatMap);
+    }
+
+
+    #[bench]
+    fn buffered_flatmap_insert_10(bh: &mut BenchHarness) {
+        bench_insert!(10, BufferedFlatMap);
+    }
+
+    #[bench]
+    fn buffered_flatmap_insert_100(bh: &mut BenchHarness) {
+        bench_insert!(100, BufferedFlatMap);
+    }
+
+    #[bench]
+    fn buffered_flatmap_insert_1000(bh: &mut BenchHarness) {
+        bench_insert!(1000, BufferedFlatMap);
+    }
+
+
     #[bench]
     fn hashmap_find_10(bh: &mut BenchHarness) {
         bench_find!(10, HashMap);
@@ -727,4 +7693,164 @@ mod bench {
     fn flatmap_find_1000(bh: &mut BenchHarness) {
         bench_find!(1000, FlatMap);
     }
+
+
+    #[bench]
+    fn frozen_flatmap_find_10(bh: &mut BenchHarness) {
+        bench_find_frozen!(10);
+    }
+
+    #[bench]
+    fn frozen_flatmap_find_100(bh: &mut BenchHarness) {
+        bench_find_frozen!(100);
+    }
+
+    #[bench]
+    fn frozen_flatmap_find_1000(bh: &mut BenchHarness) {
+        bench_find_frozen!(1000);
+    }
+
+
+    #[bench]
+    fn soa_flatmap_find_10(bh: &mut BenchHarness) {
+        bench_find!(10, SoaFlatMap);
+    }
+
+    #[bench]
+    fn soa_flatmap_find_100(bh: &mut BenchHarness) {
+        bench_find!(100, SoaFlatMap);
+    }
+
+    #[bench]
+    fn soa_flatmap_find_1000(bh: &mut BenchHarness) {
+        bench_find!(1000, SoaFlatMap);
+    }
+
+
+    #[bench]
+    fn flatmap_find_large_10(bh: &mut BenchHarness) {
+        bench_find_large!(10, FlatMap);
+    }
+
+    #[bench]
+    fn flatmap_find_large_100(bh: &mut BenchHarness) {
+        bench_find_large!(100, FlatMap);
+    }
+
+    #[bench]
+    fn flatmap_find_large_1000(bh: &mut BenchHarness) {
+        bench_find_large!(1000, FlatMap);
+    }
+
+
+    #[bench]
+    fn soa_flatmap_find_large_10(bh: &mut BenchHarness) {
+        bench_find_large!(10, SoaFlatMap);
+    }
+
+    #[bench]
+    fn soa_flatmap_find_large_100(bh: &mut BenchHarness) {
+        bench_find_large!(100, SoaFlatMap);
+    }
+
+    #[bench]
+    fn soa_flatmap_find_large_1000(bh: &mut BenchHarness) {
+        bench_find_large!(1000, SoaFlatMap);
+    }
+
+
+    fn uniform_key(i: u64) -> u64 {
+        i * 1000 + i % 7
+    }
+
+    fn skewed_key(i: u64) -> u64 {
+        i * i * i
+    }
+
+    #[bench]
+    fn flatmap_find_uniform_1000(bh: &mut BenchHarness) {
+        bench_find_keys!(1000, uniform_key, NaturalOrder);
+    }
+
+    #[bench]
+    fn flatmap_find_uniform_100000(bh: &mut BenchHarness) {
+        bench_find_keys!(100000, uniform_key, NaturalOrder);
+    }
+
+    #[bench]
+    fn flatmap_find_interpolated_uniform_1000(bh: &mut BenchHarness) {
+        bench_find_keys!(1000, uniform_key, InterpolationSearch);
+    }
+
+    #[bench]
+    fn flatmap_find_interpolated_uniform_100000(bh: &mut BenchHarness) {
+        bench_find_keys!(100000, uniform_key, InterpolationSearch);
+    }
+
+    #[bench]
+    fn flatmap_find_skewed_1000(bh: &mut BenchHarness) {
+        bench_find_keys!(1000, skewed_key, NaturalOrder);
+    }
+
+    #[bench]
+    fn flatmap_find_skewed_100000(bh: &mut BenchHarness) {
+        bench_find_keys!(100000, skewed_key, NaturalOrder);
+    }
+
+    #[bench]
+    fn flatmap_find_interpolated_skewed_1000(bh: &mut BenchHarness) {
+        bench_find_keys!(1000, skewed_key, InterpolationSearch);
+    }
+
+    #[bench]
+    fn flatmap_find_interpolated_skewed_100000(bh: &mut BenchHarness) {
+        bench_find_keys!(100000, skewed_key, InterpolationSearch);
+    }
+
+    /// Time reads on one task while `others` tasks on their own threads
+    /// read the same map as fast as they can.
+    fn bench_concurrent_read(bh: &mut BenchHarness, others: uint) {
+        let m: ConcurrentFlatMap<uint, uint> =
+            ConcurrentFlatMap::from_flatmap(range(0u, 1000).map(|i| (i, i)).collect());
+        let stop = UnsafeArc::new(AtomicBool::new(false));
+        let mut done = ~[];
+        for _ in range(0, others) {
+            let (done_port, done_chan) = comm::stream();
+            let m = m.clone();
+            let stop = stop.clone();
+            do task::spawn_sched(task::SingleThreaded) {
+                while !unsafe { (*stop.get()).load(Relaxed) } {
+                    assert_eq!(m.find_copy(&500), Some(500));
+                }
+                done_chan.send(());
+            }
+            done.push(done_port);
+        }
+        let mut k = 0u;
+        do bh.iter {
+            k = (k + 7) % 1000;
+            assert_eq!(m.find_copy(&k), Some(k));
+        }
+        unsafe {
+            (*stop.get()).store(true, Relaxed);
+        }
+        for port in done.iter() {
+            port.recv();
+        }
+    }
+
+    #[bench]
+    fn concurrent_flatmap_read_1_reader(bh: &mut BenchHarness) {
+        bench_concurrent_read(bh, 0);
+    }
+
+    #[bench]
+    fn concurrent_flatmap_read_4_readers(bh: &mut BenchHarness) {
+        bench_concurrent_read(bh, 3);
+    }
+
+    #[bench]
+    fn concurrent_flatmap_read_16_readers(bh: &mut BenchHarness) {
+        bench_concurrent_read(bh, 15);
+    }
 }